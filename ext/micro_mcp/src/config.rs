@@ -0,0 +1,169 @@
+use crate::http::HttpOptions;
+use crate::signals;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Transport used to talk to MCP clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Stdio,
+    Http,
+    Unix,
+}
+
+/// Options accepted by `MicroMcpNative.start_server`.
+///
+/// The Ruby side passes a plain hash which is converted through JSON, so the
+/// field names below are the keys Ruby callers use.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub transport: TransportKind,
+    pub host: String,
+    pub port: u16,
+    pub endpoint: String,
+    /// Origins, such as `http://localhost:6274`, that browsers may send
+    /// requests to the `http` transport from. Requests carrying any other
+    /// `Origin` header are refused.
+    pub allowed_origins: Vec<String>,
+    /// Seconds an HTTP session may go without requests before it is ended.
+    /// `None` keeps sessions until the client deletes them.
+    pub session_idle_timeout: Option<f64>,
+    /// Path of the socket used by the `unix` transport.
+    pub socket_path: Option<String>,
+    /// Name reported to clients in `serverInfo`.
+    pub name: String,
+    /// Human readable name reported to clients in `serverInfo`.
+    pub title: Option<String>,
+    /// Version reported to clients in `serverInfo`.
+    pub version: String,
+    pub instructions: Option<String>,
+    /// File to read the instructions from when starting the server.
+    pub instructions_file: Option<String>,
+    /// Seconds in-flight tool calls get to finish on shutdown before they are
+    /// aborted.
+    pub shutdown_grace: f64,
+    /// Whether the server installs any signal handlers. Disable this when the
+    /// host application traps signals itself and calls `shutdown_server`.
+    pub handle_signals: bool,
+    /// Signals that shut the server down, e.g. `["INT", "TERM"]`.
+    pub shutdown_signals: Vec<String>,
+    /// Number of Ruby threads that run tool and prompt procs.
+    pub worker_threads: usize,
+    /// Whether error results for exceptions raised by tools include a trimmed
+    /// backtrace. Meant for debugging, as it exposes file paths to clients.
+    pub error_backtraces: bool,
+    /// Maximum number of tools or prompts returned by one list request.
+    pub page_size: usize,
+    /// Seconds a tool call may run before it is aborted, unless the tool sets
+    /// its own timeout. `None` lets calls run indefinitely.
+    pub tool_timeout: Option<f64>,
+    /// Seconds a tool call cancelled by the client gets to stop by itself
+    /// before it is aborted.
+    pub cancel_grace: f64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            transport: TransportKind::Stdio,
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            endpoint: "/mcp".to_string(),
+            allowed_origins: Vec::new(),
+            session_idle_timeout: Some(1800.0),
+            socket_path: None,
+            name: "micro_mcp".to_string(),
+            title: None,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            instructions: None,
+            instructions_file: None,
+            shutdown_grace: 10.0,
+            handle_signals: true,
+            shutdown_signals: vec!["SIGINT".to_string(), "SIGTERM".to_string()],
+            worker_threads: 4,
+            error_backtraces: false,
+            page_size: 100,
+            tool_timeout: None,
+            cancel_grace: 2.0,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Signals that shut the server down, or none when signal handling is
+    /// disabled.
+    pub fn shutdown_signals(&self) -> &[String] {
+        if self.handle_signals {
+            &self.shutdown_signals
+        } else {
+            &[]
+        }
+    }
+
+    /// Checks the options that depend on the chosen transport.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.transport == TransportKind::Unix && self.socket_path.is_none() {
+            return Err("the unix transport requires a socket_path".to_string());
+        }
+        if !(self.shutdown_grace >= 0.0 && Duration::try_from_secs_f64(self.shutdown_grace).is_ok())
+        {
+            return Err(format!("invalid shutdown_grace: {}", self.shutdown_grace));
+        }
+        if !(self.cancel_grace >= 0.0 && Duration::try_from_secs_f64(self.cancel_grace).is_ok()) {
+            return Err(format!("invalid cancel_grace: {}", self.cancel_grace));
+        }
+        if let Some(name) = self
+            .shutdown_signals
+            .iter()
+            .find(|name| signals::canonical_name(name).is_none())
+        {
+            return Err(format!("unsupported signal: {name}"));
+        }
+        if self.worker_threads == 0 {
+            return Err("worker_threads must be at least 1".to_string());
+        }
+        if self.page_size == 0 {
+            return Err("page_size must be at least 1".to_string());
+        }
+        if let Some(timeout) = self.tool_timeout {
+            if !(timeout > 0.0 && Duration::try_from_secs_f64(timeout).is_ok()) {
+                return Err(format!("invalid tool_timeout: {timeout}"));
+            }
+        }
+        if let Some(timeout) = self.session_idle_timeout {
+            if !(timeout > 0.0 && Duration::try_from_secs_f64(timeout).is_ok()) {
+                return Err(format!("invalid session_idle_timeout: {timeout}"));
+            }
+        }
+        if self.instructions.is_some() && self.instructions_file.is_some() {
+            return Err("instructions and instructions_file are mutually exclusive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Replaces `instructions_file` with the contents of that file.
+    pub fn load_instructions(&mut self) -> std::io::Result<()> {
+        if let Some(path) = self.instructions_file.take() {
+            let contents = std::fs::read_to_string(&path).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("could not read {path}: {e}"))
+            })?;
+            self.instructions = Some(contents);
+        }
+        Ok(())
+    }
+
+    pub fn http_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn http_options(&self) -> HttpOptions {
+        HttpOptions {
+            endpoint: self.endpoint.clone(),
+            allowed_origins: self.allowed_origins.clone(),
+            session_idle_timeout: self.session_idle_timeout.map(Duration::from_secs_f64),
+        }
+    }
+}
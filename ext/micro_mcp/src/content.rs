@@ -0,0 +1,206 @@
+//! `MicroMcp::Content`, the content blocks a tool can return instead of plain
+//! text, and `MicroMcp::ErrorResult`, which reports a failure without
+//! raising.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use magnus::{
+    function, method, prelude::*, scan_args::get_kwargs, scan_args::scan_args, Error, RArray,
+    RModule, RString, Ruby, TryConvert, Value,
+};
+use rust_mcp_sdk::schema::{
+    AudioContent, BlobResourceContents, ContentBlock, EmbeddedResource, ImageContent, ResourceLink,
+    TextContent, TextResourceContents,
+};
+
+use crate::server::json_value_to_ruby_value;
+
+/// One content block of a tool result.
+#[magnus::wrap(class = "MicroMcp::Content", free_immediately, frozen_shareable)]
+pub struct Content(ContentBlock);
+
+impl Content {
+    /// `Content.text(text)`
+    fn text(text: String) -> Self {
+        Self(TextContent::new(text, None, None).into())
+    }
+
+    /// `Content.image(data, mime_type:)`, where `data` holds the raw bytes.
+    fn image(args: &[Value]) -> Result<Self, Error> {
+        let (data, mime_type) = binary_args(args)?;
+        Ok(Self(ImageContent::new(data, mime_type, None, None).into()))
+    }
+
+    /// `Content.audio(data, mime_type:)`, where `data` holds the raw bytes.
+    fn audio(args: &[Value]) -> Result<Self, Error> {
+        let (data, mime_type) = binary_args(args)?;
+        Ok(Self(AudioContent::new(data, mime_type, None, None).into()))
+    }
+
+    /// `Content.resource_link(uri:, name:, title: nil, description: nil,
+    /// mime_type: nil, size: nil)`
+    fn resource_link(args: &[Value]) -> Result<Self, Error> {
+        type Optional = (
+            Option<Option<String>>,
+            Option<Option<String>>,
+            Option<Option<String>>,
+            Option<Option<i64>>,
+        );
+        let args = scan_args::<(), (), (), (), _, ()>(args)?;
+        let kwargs = get_kwargs::<_, (String, String), Optional, ()>(
+            args.keywords,
+            &["uri", "name"],
+            &["title", "description", "mime_type", "size"],
+        )?;
+        let (uri, name) = kwargs.required;
+        let (title, description, mime_type, size) = kwargs.optional;
+        Ok(Self(
+            ResourceLink::new(
+                name,
+                uri,
+                None,
+                description.flatten(),
+                None,
+                mime_type.flatten(),
+                size.flatten(),
+                title.flatten(),
+            )
+            .into(),
+        ))
+    }
+
+    /// `Content.resource(uri:, text: nil, blob: nil, mime_type: nil)`, an
+    /// embedded resource with either `text` or the raw bytes in `blob`.
+    fn resource(ruby: &Ruby, args: &[Value]) -> Result<Self, Error> {
+        type Optional = (
+            Option<Option<String>>,
+            Option<Option<RString>>,
+            Option<Option<String>>,
+        );
+        let args = scan_args::<(), (), (), (), _, ()>(args)?;
+        let kwargs = get_kwargs::<_, (String,), Optional, ()>(
+            args.keywords,
+            &["uri"],
+            &["text", "blob", "mime_type"],
+        )?;
+        let (uri,) = kwargs.required;
+        let (text, blob, mime_type) = kwargs.optional;
+        let contents = match (text.flatten(), blob.flatten()) {
+            (Some(text), None) => TextResourceContents {
+                meta: None,
+                mime_type: mime_type.flatten(),
+                text,
+                uri,
+            }
+            .into(),
+            (None, Some(blob)) => BlobResourceContents {
+                blob: encode(blob),
+                meta: None,
+                mime_type: mime_type.flatten(),
+                uri,
+            }
+            .into(),
+            _ => {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    "exactly one of text: and blob: is required",
+                ))
+            }
+        };
+        Ok(Self(EmbeddedResource::new(contents, None, None).into()))
+    }
+
+    /// The block as it is sent to clients.
+    fn to_h(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        let json = serde_json::to_value(&rb_self.0)
+            .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))?;
+        json_value_to_ruby_value(ruby, &json)
+    }
+}
+
+/// A tool result marked as an error, for failures the model should see and
+/// act on, such as an unknown city, rather than bugs in the tool.
+#[magnus::wrap(class = "MicroMcp::ErrorResult", free_immediately, frozen_shareable)]
+pub struct ErrorResult(Vec<ContentBlock>);
+
+impl ErrorResult {
+    /// `ErrorResult.new(*content)`, where each item is a String or a
+    /// `Content`.
+    fn new(ruby: &Ruby, content: RArray) -> Result<Self, Error> {
+        if content.is_empty() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "an error result needs at least one message or content block",
+            ));
+        }
+        let blocks = content
+            .into_iter()
+            .map(|item| match <&Content>::try_convert(item) {
+                Ok(content) => Ok(content.0.clone()),
+                Err(_) => Ok(TextContent::new(String::try_convert(item)?, None, None).into()),
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self(blocks))
+    }
+}
+
+/// Parses `(data, mime_type:)` and returns the data base64 encoded.
+fn binary_args(args: &[Value]) -> Result<(String, String), Error> {
+    let args = scan_args::<(RString,), (), (), (), _, ()>(args)?;
+    let kwargs = get_kwargs::<_, (String,), (), ()>(args.keywords, &["mime_type"], &[])?;
+    let (data,) = args.required;
+    let (mime_type,) = kwargs.required;
+    Ok((encode(data), mime_type))
+}
+
+fn encode(data: RString) -> String {
+    // SAFETY: no Ruby code runs while the bytes are borrowed
+    BASE64.encode(unsafe { data.as_slice() })
+}
+
+/// Returns the content blocks of an `ErrorResult` a tool returned, or `None`
+/// for any other value.
+pub fn error_blocks(value: Value) -> Option<Vec<ContentBlock>> {
+    <&ErrorResult>::try_convert(value)
+        .ok()
+        .map(|result| result.0.clone())
+}
+
+/// Returns the content blocks a tool returned: a `Content`, or a non-empty
+/// Array of them. Returns `None` for any other value.
+pub fn blocks(ruby: &Ruby, value: Value) -> Result<Option<Vec<ContentBlock>>, Error> {
+    if let Ok(content) = <&Content>::try_convert(value) {
+        return Ok(Some(vec![content.0.clone()]));
+    }
+    let Some(array) = RArray::from_value(value) else {
+        return Ok(None);
+    };
+    let items: Vec<Value> = array.to_vec()?;
+    let blocks: Vec<ContentBlock> = items
+        .iter()
+        .filter_map(|item| <&Content>::try_convert(*item).ok())
+        .map(|content| content.0.clone())
+        .collect();
+    match blocks.len() {
+        0 => Ok(None),
+        len if len == items.len() => Ok(Some(blocks)),
+        _ => Err(Error::new(
+            ruby.exception_type_error(),
+            "an Array of MicroMcp::Content cannot contain other values",
+        )),
+    }
+}
+
+/// Defines `MicroMcp::Content` and `MicroMcp::ErrorResult`.
+pub fn define(ruby: &Ruby, parent: RModule) -> Result<(), Error> {
+    let class = parent.define_class("Content", ruby.class_object())?;
+    class.define_singleton_method("text", function!(Content::text, 1))?;
+    class.define_singleton_method("image", function!(Content::image, -1))?;
+    class.define_singleton_method("audio", function!(Content::audio, -1))?;
+    class.define_singleton_method("resource_link", function!(Content::resource_link, -1))?;
+    class.define_singleton_method("resource", function!(Content::resource, -1))?;
+    class.define_method("to_h", method!(Content::to_h, 0))?;
+
+    let class = parent.define_class("ErrorResult", ruby.class_object())?;
+    class.define_singleton_method("new", function!(ErrorResult::new, -2))?;
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Pool of Ruby threads that run tool and prompt procs.
+//!
+//! Sessions are driven without the GVL, so instead of calling into Ruby
+//! themselves they queue jobs here and await the outcome on a channel. Each
+//! job is picked up by one of a fixed number of Ruby threads, which lets
+//! several procs make progress at once while a Ruby thread that is killed to
+//! abort its job is replaced to keep the pool at its size.
+
+use crate::utils::block_on;
+use magnus::{Error, Ruby};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+
+/// Work run on a pool thread with the GVL held. Only returns an error when the
+/// thread is being killed.
+pub type Job = Box<dyn FnOnce(&Ruby) -> Result<(), Error> + Send>;
+
+#[derive(Default)]
+pub struct Dispatcher {
+    /// Set while the pool is running.
+    jobs: Mutex<Option<mpsc::UnboundedSender<Job>>>,
+}
+
+struct Queue {
+    jobs: tokio::sync::Mutex<mpsc::UnboundedReceiver<Job>>,
+    /// Runs the threads' waits for jobs.
+    runtime: Handle,
+}
+
+impl Queue {
+    async fn next(&self) -> Option<Job> {
+        self.jobs.lock().await.recv().await
+    }
+}
+
+impl Dispatcher {
+    /// Starts `size` Ruby threads. Must be called with the GVL held.
+    pub fn start(&self, ruby: &Ruby, size: usize, runtime: Handle) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queue = Arc::new(Queue {
+            jobs: tokio::sync::Mutex::new(receiver),
+            runtime,
+        });
+        for _ in 0..size {
+            spawn_worker(ruby, queue.clone());
+        }
+        *self.jobs.lock().unwrap() = Some(sender);
+    }
+
+    /// Lets the threads exit once the queued jobs have run.
+    pub fn stop(&self) {
+        self.jobs.lock().unwrap().take();
+    }
+
+    /// Queues `job`, or returns it when the pool is not running.
+    pub fn submit(&self, job: Job) -> Result<(), Job> {
+        match self.jobs.lock().unwrap().as_ref() {
+            Some(sender) => sender.send(job).map_err(|e| e.0),
+            None => Err(job),
+        }
+    }
+}
+
+fn spawn_worker(ruby: &Ruby, queue: Arc<Queue>) {
+    ruby.thread_create_from_fn(move |ruby| work(ruby, queue));
+}
+
+fn work(ruby: &Ruby, queue: Arc<Queue>) -> Result<(), Error> {
+    // Threads are only killed while waiting for a job when Ruby exits, so
+    // they are not replaced then.
+    while let Some(job) = block_on(&queue.runtime, queue.next())? {
+        // A kill meant for the job can arrive just after it returned, and is
+        // taken here rather than by the next job.
+        if let Err(e) = job(ruby).and_then(|()| ruby.thread_check_ints()) {
+            spawn_worker(ruby, queue);
+            // let Thread#kill finish unwinding this thread
+            return Err(e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Helpers shared by the transports that host many client sessions in one
+//! process.
+//!
+//! Every session gets its own [`SessionRuntime`] reading and writing
+//! newline-delimited JSON-RPC on a pair of in-memory duplex streams. The
+//! transport pumps bytes between its connections and the client ends of those
+//! streams on Tokio worker threads, while the runtimes themselves are driven
+//! by [`run_sessions`] on the thread that started the server, because that is
+//! the Ruby thread the tool handlers are allowed to re-acquire the GVL on.
+
+use crate::logging::Logger;
+use crate::session::{Peer, SessionHandler, SessionRuntime};
+use rust_mcp_sdk::{schema::InitializeResult, McpServer};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, LocalSet};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Pause after an `accept` error such as running out of file descriptors,
+/// which would otherwise be returned again straight away.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Client side of a session created by [`create_session`].
+pub struct ClientEnds {
+    /// Messages written here are read by the session's runtime.
+    pub input: DuplexStream,
+    /// Messages written by the session's runtime are read from here.
+    pub output: DuplexStream,
+}
+
+pub fn create_session<H: SessionHandler>(
+    server_details: InitializeResult,
+    logger: Logger,
+    new_handler: impl FnOnce(Arc<Peer>) -> H,
+) -> (SessionRuntime, ClientEnds) {
+    let (input, server_in) = tokio::io::duplex(BUFFER_SIZE);
+    let (server_out, output) = tokio::io::duplex(BUFFER_SIZE);
+    let server = SessionRuntime::new(server_details, logger, new_handler, server_in, server_out);
+    (server, ClientEnds { input, output })
+}
+
+/// Deals with an error returned by `accept`, which does not stop the server:
+/// errors about the connection being accepted are ignored, others are logged
+/// and followed by a pause before the next attempt.
+pub async fn accept_failed(error: std::io::Error, logger: &Logger) {
+    if matches!(
+        error.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+    ) {
+        return;
+    }
+    logger.error(format!("Failed to accept a connection: {error}"));
+    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+}
+
+/// Aborts the accept loop, and with it every open connection, when the
+/// transport is dropped.
+pub struct AbortOnDrop(pub JoinHandle<std::io::Result<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Drives every runtime received on `runtimes` on the current thread until
+/// the accept loop ends. `on_end` is called with the session key once a
+/// runtime has finished.
+pub async fn run_sessions<K: 'static>(
+    mut accept: AbortOnDrop,
+    mut runtimes: mpsc::UnboundedReceiver<(K, SessionRuntime)>,
+    on_end: impl Fn(K) + Clone + 'static,
+) -> std::io::Result<()> {
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            loop {
+                tokio::select! {
+                    result = &mut accept.0 => {
+                        return result.unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                    }
+                    Some((key, server)) = runtimes.recv() => {
+                        let on_end = on_end.clone();
+                        tokio::task::spawn_local(async move {
+                            let _ = server.start().await;
+                            on_end(key);
+                        });
+                    }
+                }
+            }
+        })
+        .await
+}
@@ -0,0 +1,94 @@
+use magnus::{exception::ExceptionClass, prelude::*, value::Lazy, Error, RHash, RModule, Ruby};
+use rust_mcp_sdk::error::McpSdkError;
+
+static TRANSPORT_ERROR: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| error_class(ruby, "TransportError"));
+static PROTOCOL_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| error_class(ruby, "ProtocolError"));
+
+fn error_class(ruby: &Ruby, name: &str) -> ExceptionClass {
+    ruby.class_object()
+        .const_get::<_, RModule>("MicroMcp")
+        .and_then(|module| module.const_get(name))
+        .expect("MicroMcp errors are defined at init")
+}
+
+/// Defines `MicroMcp::Error` and the exceptions raised when a server fails.
+pub fn define(ruby: &Ruby, parent: RModule) -> Result<(), Error> {
+    let base = parent.define_error("Error", ruby.exception_standard_error())?;
+    parent.define_error("TransportError", base)?;
+    parent.define_error("ProtocolError", base)?;
+    Ok(())
+}
+
+/// Why a running server stopped with an error.
+#[derive(Debug)]
+pub enum ServeError {
+    /// The connection to the client, or the listener, failed.
+    Transport(String),
+    /// The client sent something the server could not make sense of.
+    Protocol(String),
+}
+
+impl ServeError {
+    pub fn into_ruby_error(self, ruby: &Ruby) -> Error {
+        match self {
+            ServeError::Transport(message) => Error::new(ruby.get_inner(&TRANSPORT_ERROR), message),
+            ServeError::Protocol(message) => Error::new(ruby.get_inner(&PROTOCOL_ERROR), message),
+        }
+    }
+}
+
+impl From<std::io::Error> for ServeError {
+    fn from(e: std::io::Error) -> Self {
+        ServeError::Transport(e.to_string())
+    }
+}
+
+impl From<McpSdkError> for ServeError {
+    fn from(e: McpSdkError) -> Self {
+        match e {
+            McpSdkError::IoError(_) | McpSdkError::TransportError(_) => {
+                ServeError::Transport(e.to_string())
+            }
+            _ => ServeError::Protocol(e.to_string()),
+        }
+    }
+}
+
+/// Why a server stopped without an error.
+#[derive(Debug, Clone, Copy)]
+pub enum StopReason {
+    /// The client closed the connection, e.g. stdin reached end of file.
+    ClientDisconnected,
+    /// `shutdown_server` was called.
+    ShutdownRequested,
+    /// The process received the named signal.
+    Signal(&'static str),
+}
+
+/// Outcome of a server run that ended without an error.
+#[derive(Debug)]
+pub struct Stopped {
+    pub reason: StopReason,
+    /// Names of the calls aborted after the shutdown grace period: tool
+    /// names, and `prompt:` or `resource:` followed by a prompt name or a
+    /// resource URI.
+    pub aborted_calls: Vec<String>,
+}
+
+impl Stopped {
+    /// Converts to the hash returned by `start_server`, which the Ruby side
+    /// wraps in `MicroMcp::StopReason`.
+    pub fn into_hash(self, ruby: &Ruby) -> Result<RHash, Error> {
+        let (reason, signal) = match self.reason {
+            StopReason::ClientDisconnected => ("client_disconnected", None),
+            StopReason::ShutdownRequested => ("shutdown_requested", None),
+            StopReason::Signal(name) => ("signal", Some(name)),
+        };
+        let hash = ruby.hash_new();
+        hash.aset("reason", reason)?;
+        hash.aset("signal", signal)?;
+        hash.aset("aborted_calls", self.aborted_calls)?;
+        Ok(hash)
+    }
+}
@@ -0,0 +1,481 @@
+//! MCP Streamable HTTP transport.
+//!
+//! Each client session gets its own `SessionRuntime`, wired to the HTTP layer
+//! through in-memory duplex streams. A POSTed request is written into the
+//! session and answered with a `text/event-stream` response that carries the
+//! server messages emitted while handling it and ends with the JSON-RPC
+//! response. A GET opens a standalone stream for server initiated messages
+//! that do not belong to a request, which are held until it is opened, and a
+//! DELETE ends the session.
+//!
+//! Requests sent by browsers from an origin that is not explicitly allowed
+//! are refused, so a web page cannot reach a server on localhost through DNS
+//! rebinding. Sessions that go without requests for too long are ended as if
+//! the client had deleted them.
+//!
+//! HTTP connections are served on Tokio worker threads, while the session
+//! runtimes are driven by [`duplex::run_sessions`] on the thread that called
+//! [`serve`].
+
+use crate::duplex::{self, AbortOnDrop};
+use crate::logging::Logger;
+use crate::session::{Peer, SessionHandler, SessionRuntime};
+use bytes::Bytes;
+use futures::StreamExt;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Empty, Full, StreamBody};
+use hyper::{
+    body::{Frame, Incoming},
+    header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, ORIGIN},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use rust_mcp_sdk::schema::{InitializeResult, RequestId};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+const SESSION_HEADER: &str = "mcp-session-id";
+
+/// Number of messages held for a session without a standalone stream before
+/// the oldest are dropped.
+const BACKLOG_CAPACITY: usize = 1024;
+
+type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+/// How the HTTP transport treats its clients.
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    /// Path the transport is served on.
+    pub endpoint: String,
+    /// Values of the `Origin` header requests are accepted with. Requests
+    /// without one do not come from a browser and are always accepted.
+    pub allowed_origins: Vec<String>,
+    /// Time after which a session without requests or open streams is ended.
+    pub session_idle_timeout: Option<Duration>,
+}
+
+struct Session {
+    inbound: tokio::sync::Mutex<DuplexStream>,
+    streams: Mutex<Streams>,
+    /// When the client last sent a request for the session.
+    last_request: Mutex<Instant>,
+}
+
+#[derive(Default)]
+struct Streams {
+    /// Open POST responses, keyed by the JSON encoded id of the request they answer.
+    pending: HashMap<String, mpsc::UnboundedSender<String>>,
+    /// Stream opened with GET for messages that are not tied to a request.
+    standalone: Option<mpsc::UnboundedSender<String>>,
+    /// Messages for the standalone stream waiting for it to be opened.
+    backlog: VecDeque<String>,
+    /// The JSON encoded id of the open request each JSON encoded progress
+    /// token was passed with.
+    progress: HashMap<String, String>,
+}
+
+type Sessions = Arc<Mutex<HashMap<String, Arc<Session>>>>;
+
+struct HttpState<F> {
+    options: HttpOptions,
+    server_details: InitializeResult,
+    logger: Logger,
+    new_handler: F,
+    sessions: Sessions,
+    runtimes: mpsc::UnboundedSender<(String, SessionRuntime)>,
+}
+
+/// Serves MCP over Streamable HTTP on `addr` until the returned future is
+/// dropped. Only fails when `addr` cannot be bound.
+pub async fn serve<H, F>(
+    addr: &str,
+    options: HttpOptions,
+    server_details: InitializeResult,
+    logger: Logger,
+    new_handler: F,
+) -> std::io::Result<()>
+where
+    H: SessionHandler,
+    F: Fn(Arc<Peer>) -> H + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    logger.info(format!(
+        "MCP Streamable HTTP endpoint listening on http://{}{}",
+        listener.local_addr()?,
+        options.endpoint
+    ));
+
+    let (runtimes_tx, runtimes_rx) = mpsc::unbounded_channel();
+    let sessions: Sessions = Arc::default();
+    let state = Arc::new(HttpState {
+        options,
+        server_details,
+        logger,
+        new_handler,
+        sessions: sessions.clone(),
+        runtimes: runtimes_tx,
+    });
+    let accept = AbortOnDrop(tokio::spawn(accept_loop(listener, state)));
+    duplex::run_sessions(accept, runtimes_rx, move |id: String| {
+        sessions.lock().unwrap().remove(&id);
+    })
+    .await
+}
+
+async fn accept_loop<H, F>(listener: TcpListener, state: Arc<HttpState<F>>) -> std::io::Result<()>
+where
+    H: SessionHandler,
+    F: Fn(Arc<Peer>) -> H + Send + Sync + 'static,
+{
+    let mut connections = JoinSet::new();
+    // aborted along with the connections once the loop ends
+    if let Some(timeout) = state.options.session_idle_timeout {
+        let state = state.clone();
+        connections.spawn(async move { match expire_idle_sessions(state, timeout).await {} });
+    }
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                duplex::accept_failed(e, &state.logger).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        connections.spawn(async move {
+            let service = service_fn(move |req| handle_request(state.clone(), req));
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+        // reap finished connections so the set does not grow unbounded
+        while connections.try_join_next().is_some() {}
+    }
+}
+
+async fn handle_request<H, F>(
+    state: Arc<HttpState<F>>,
+    req: Request<Incoming>,
+) -> Result<Response<Body>, Infallible>
+where
+    H: SessionHandler,
+    F: Fn(Arc<Peer>) -> H + Send + Sync + 'static,
+{
+    if req.uri().path() != state.options.endpoint {
+        return Ok(text_response(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    if let Some(origin) = req.headers().get(ORIGIN) {
+        let allowed = origin.to_str().is_ok_and(|origin| {
+            state
+                .options
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        });
+        if !allowed {
+            return Ok(text_response(StatusCode::FORBIDDEN, "Origin not allowed"));
+        }
+    }
+    let session_id = req
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    let response = match *req.method() {
+        Method::POST => handle_post(&state, session_id, req).await,
+        Method::GET => handle_get(&state, session_id),
+        Method::DELETE => handle_delete(&state, session_id).await,
+        _ => text_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed"),
+    };
+    Ok(response)
+}
+
+async fn handle_post<H, F>(
+    state: &HttpState<F>,
+    session_id: Option<String>,
+    req: Request<Incoming>,
+) -> Response<Body>
+where
+    H: SessionHandler,
+    F: Fn(Arc<Peer>) -> H + Send + Sync + 'static,
+{
+    let body = match req.into_body().collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let message: JsonValue = match serde_json::from_slice(&body) {
+        Ok(JsonValue::Object(map)) => JsonValue::Object(map),
+        Ok(_) => {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                "Expected a single JSON-RPC message object",
+            )
+        }
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+
+    let method = message.get("method").and_then(|m| m.as_str());
+    let request_id = message.get("id").filter(|_| method.is_some());
+
+    let (session_id, session, created) = match session_id {
+        Some(id) => match lookup_session(state, &id) {
+            Some(session) => (id, session, false),
+            None => return text_response(StatusCode::NOT_FOUND, "Unknown session"),
+        },
+        None if method == Some("initialize") => match create_session(state) {
+            Ok((id, session)) => (id, session, true),
+            Err(e) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, &e),
+        },
+        None => {
+            return text_response(StatusCode::BAD_REQUEST, "Missing Mcp-Session-Id header");
+        }
+    };
+
+    // a cancelled request is not answered, so end the stream waiting for it
+    if method == Some("notifications/cancelled") {
+        if let Some(id) = message.pointer("/params/requestId") {
+            session.streams.lock().unwrap().close(&id.to_string());
+        }
+    }
+
+    let response_stream = request_id.map(|id| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut streams = session.streams.lock().unwrap();
+        streams.pending.insert(id.to_string(), tx);
+        if let Some(token) = message.pointer("/params/_meta/progressToken") {
+            streams.progress.insert(token.to_string(), id.to_string());
+        }
+        rx
+    });
+
+    let line = format!("{message}\n");
+    let written = {
+        let mut inbound = session.inbound.lock().await;
+        inbound.write_all(line.as_bytes()).await
+    };
+    if written.is_err() {
+        state.sessions.lock().unwrap().remove(&session_id);
+        return text_response(StatusCode::NOT_FOUND, "Session has ended");
+    }
+
+    let mut response = match response_stream {
+        Some(rx) => event_stream_response(rx),
+        None => empty_response(StatusCode::ACCEPTED),
+    };
+    if created {
+        if let Ok(value) = HeaderValue::from_str(&session_id) {
+            response.headers_mut().insert(SESSION_HEADER, value);
+        }
+    }
+    response
+}
+
+fn handle_get<F>(state: &HttpState<F>, session_id: Option<String>) -> Response<Body> {
+    let Some(session) = session_id.and_then(|id| lookup_session(state, &id)) else {
+        return text_response(StatusCode::NOT_FOUND, "Unknown session");
+    };
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut streams = session.streams.lock().unwrap();
+    for line in streams.backlog.drain(..) {
+        let _ = tx.send(line);
+    }
+    streams.standalone = Some(tx);
+    event_stream_response(rx)
+}
+
+async fn handle_delete<F>(state: &HttpState<F>, session_id: Option<String>) -> Response<Body> {
+    let removed = session_id.and_then(|id| state.sessions.lock().unwrap().remove(&id));
+    match removed {
+        Some(session) => {
+            session.end().await;
+            empty_response(StatusCode::OK)
+        }
+        None => text_response(StatusCode::NOT_FOUND, "Unknown session"),
+    }
+}
+
+/// Returns session `id`, which has just received a request.
+fn lookup_session<F>(state: &HttpState<F>, id: &str) -> Option<Arc<Session>> {
+    let session = state.sessions.lock().unwrap().get(id).cloned()?;
+    *session.last_request.lock().unwrap() = Instant::now();
+    Some(session)
+}
+
+/// Ends the sessions idle for `timeout`, checking at a fraction of it. Never
+/// completes.
+async fn expire_idle_sessions<F>(state: Arc<HttpState<F>>, timeout: Duration) -> Infallible {
+    let mut interval = tokio::time::interval((timeout / 4).min(Duration::from_secs(60)));
+    loop {
+        interval.tick().await;
+        let mut expired = Vec::new();
+        state.sessions.lock().unwrap().retain(|_, session| {
+            let idle = session.is_idle(timeout);
+            if idle {
+                expired.push(session.clone());
+            }
+            !idle
+        });
+        for session in expired {
+            session.end().await;
+        }
+    }
+}
+
+impl Streams {
+    /// Ends the POST stream of request `key` and forgets its progress token.
+    fn close(&mut self, key: &str) -> Option<mpsc::UnboundedSender<String>> {
+        self.progress.retain(|_, request| *request != key);
+        self.pending.remove(key)
+    }
+
+    /// Returns the key of the request that `message`, a notification or
+    /// request from the server, was sent while answering: the request that
+    /// passed the token of a progress notification, or the one a server
+    /// request was made for.
+    fn related(&self, message: &JsonValue, peer: &Peer) -> Option<String> {
+        if let Some(token) = message.pointer("/params/progressToken") {
+            return self.progress.get(&token.to_string()).cloned();
+        }
+        let id = RequestId::deserialize(message.get("id")?).ok()?;
+        let related = peer.related_request(&id)?;
+        serde_json::to_string(&related).ok()
+    }
+
+    /// Sends `line` on the POST stream of request `key` if it is still open,
+    /// or else on the standalone stream, holding it until one is opened.
+    fn deliver(&mut self, key: Option<String>, line: String) {
+        let line = match key.and_then(|key| self.pending.get(&key)) {
+            Some(tx) => match tx.send(line) {
+                Ok(()) => return,
+                Err(unsent) => unsent.0,
+            },
+            None => line,
+        };
+        let line = match &self.standalone {
+            Some(tx) => match tx.send(line) {
+                Ok(()) => return,
+                Err(unsent) => {
+                    self.standalone = None;
+                    unsent.0
+                }
+            },
+            None => line,
+        };
+        if self.backlog.len() == BACKLOG_CAPACITY {
+            self.backlog.pop_front();
+        }
+        self.backlog.push_back(line);
+    }
+}
+
+impl Session {
+    /// Whether the session received no request for `timeout` and has no
+    /// response stream open.
+    fn is_idle(&self, timeout: Duration) -> bool {
+        let streams = self.streams.lock().unwrap();
+        self.last_request.lock().unwrap().elapsed() >= timeout
+            && streams
+                .pending
+                .values()
+                .chain(&streams.standalone)
+                .all(|tx| tx.is_closed())
+    }
+
+    async fn end(&self) {
+        // closing the inbound side ends the session's runtime
+        let _ = self.inbound.lock().await.shutdown().await;
+    }
+}
+
+fn create_session<H, F>(state: &HttpState<F>) -> Result<(String, Arc<Session>), String>
+where
+    H: SessionHandler,
+    F: Fn(Arc<Peer>) -> H + Send + Sync + 'static,
+{
+    let (server, ends) = duplex::create_session(
+        state.server_details.clone(),
+        state.logger.clone(),
+        &state.new_handler,
+    );
+
+    let peer = server.peer();
+    let id = uuid::Uuid::new_v4().to_string();
+    let session = Arc::new(Session {
+        inbound: tokio::sync::Mutex::new(ends.input),
+        streams: Mutex::default(),
+        last_request: Mutex::new(Instant::now()),
+    });
+    state
+        .sessions
+        .lock()
+        .unwrap()
+        .insert(id.clone(), session.clone());
+    tokio::spawn(route_outbound(session.clone(), peer, ends.output));
+    state
+        .runtimes
+        .send((id.clone(), server))
+        .map_err(|_| "HTTP server is shutting down".to_string())?;
+    Ok((id, session))
+}
+
+/// Forwards messages written by the session's runtime to the HTTP stream they
+/// belong to. Responses go to the POST that issued the request, and so do
+/// progress notifications and server requests sent while answering it. Other
+/// messages, and those whose request has already been answered, go to the
+/// standalone GET stream.
+async fn route_outbound(session: Arc<Session>, peer: Arc<Peer>, output: DuplexStream) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Ok(message) = serde_json::from_str::<JsonValue>(&line) else {
+            continue;
+        };
+        let mut streams = session.streams.lock().unwrap();
+        if message.get("method").is_none() {
+            let key = message.get("id").map(|id| id.to_string());
+            if let Some(tx) = key.and_then(|key| streams.close(&key)) {
+                let _ = tx.send(line);
+            }
+            continue;
+        }
+        let key = streams.related(&message, &peer);
+        streams.deliver(key, line);
+    }
+}
+
+fn event_stream_response(mut rx: mpsc::UnboundedReceiver<String>) -> Response<Body> {
+    let events = futures::stream::poll_fn(move |cx| rx.poll_recv(cx)).map(|line| {
+        Ok::<_, Infallible>(Frame::data(Bytes::from(format!(
+            "event: message\ndata: {line}\n\n"
+        ))))
+    });
+    let mut response = Response::new(StreamBody::new(events).boxed_unsync());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Empty::new().boxed_unsync());
+    *response.status_mut() = status;
+    response
+}
+
+fn text_response(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Full::new(Bytes::from(message.to_string())).boxed_unsync());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    response
+}
@@ -1,4 +1,16 @@
+mod config;
+mod content;
+mod dispatcher;
+mod duplex;
+mod errors;
+mod http;
+mod logging;
+mod schema;
 mod server;
+mod session;
+mod signals;
+mod unix_socket;
+mod uri_template;
 mod utils;
 
 use magnus::{function, method, prelude::*, Error, Ruby};
@@ -6,17 +18,91 @@ use magnus::{function, method, prelude::*, Error, Ruby};
 #[magnus::init]
 fn init(ruby: &Ruby) -> Result<(), Error> {
     let native = ruby.define_module("MicroMcpNative")?;
-    native.define_singleton_method("start_server", function!(server::start_server, 0))?;
-    native.define_singleton_method("shutdown_server", function!(server::shutdown_server, 0))?;
-    native.define_singleton_method("register_tool", function!(server::register_tool, 4))?;
+    native.define_singleton_method("start_server", function!(server::start_server, -1))?;
+    native.define_singleton_method("shutdown_server", function!(server::shutdown_server, -1))?;
+    native.define_singleton_method("register_tool", function!(server::register_tool, 5))?;
     native.define_singleton_method("register_prompt", function!(server::register_prompt, 4))?;
+    native.define_singleton_method("register_resource", function!(server::register_resource, 5))?;
+    native.define_singleton_method(
+        "register_resource_template",
+        function!(server::register_resource_template, 5),
+    )?;
+    native.define_singleton_method(
+        "notify_resource_updated",
+        function!(server::notify_resource_updated, 1),
+    )?;
+
+    let server_class = native.define_class("Server", ruby.class_object())?;
+    server_class.define_singleton_method("new", function!(server::NativeServer::default, 0))?;
+    server_class.define_singleton_method(
+        "default",
+        function!(server::NativeServer::default_instance, 0),
+    )?;
+    server_class.define_method(
+        "register_tool",
+        method!(server::NativeServer::register_tool, 5),
+    )?;
+    server_class.define_method(
+        "register_prompt",
+        method!(server::NativeServer::register_prompt, 4),
+    )?;
+    server_class.define_method(
+        "register_resource",
+        method!(server::NativeServer::register_resource, 5),
+    )?;
+    server_class.define_method(
+        "register_resource_template",
+        method!(server::NativeServer::register_resource_template, 5),
+    )?;
+    server_class.define_method(
+        "notify_resource_updated",
+        method!(server::NativeServer::notify_resource_updated, 1),
+    )?;
+    server_class.define_method(
+        "unregister_tool",
+        method!(server::NativeServer::unregister_tool, 1),
+    )?;
+    server_class.define_method(
+        "unregister_prompt",
+        method!(server::NativeServer::unregister_prompt, 1),
+    )?;
+    server_class.define_method("clear_tools", method!(server::NativeServer::clear_tools, 0))?;
+    server_class.define_method(
+        "clear_prompts",
+        method!(server::NativeServer::clear_prompts, 0),
+    )?;
+    server_class.define_method("tool_names", method!(server::NativeServer::tool_names, 0))?;
+    server_class.define_method(
+        "prompt_names",
+        method!(server::NativeServer::prompt_names, 0),
+    )?;
+    server_class.define_method("begin_run", method!(server::NativeServer::begin_run, 0))?;
+    server_class.define_method(
+        "start_server",
+        method!(server::NativeServer::start_server, -1),
+    )?;
+    server_class.define_method(
+        "shutdown_server",
+        method!(server::NativeServer::shutdown_server, -1),
+    )?;
+    server_class.define_method("on_reload", method!(server::NativeServer::on_reload, 1))?;
 
     let parent = ruby.define_module("MicroMcp")?;
+    errors::define(ruby, parent)?;
+    content::define(ruby, parent)?;
     let class = parent.define_class("Runtime", ruby.class_object())?;
     class.define_method(
         "is_initialized",
         method!(server::RubyMcpServer::is_initialized, 0),
     )?;
+    class.define_method(
+        "cancelled?",
+        method!(server::RubyMcpServer::is_cancelled, 0),
+    )?;
+    class.define_method(
+        "report_progress",
+        method!(server::RubyMcpServer::report_progress, -1),
+    )?;
     class.define_method(
         "client_supports_sampling",
         method!(server::RubyMcpServer::client_supports_sampling, 0),
@@ -0,0 +1,52 @@
+//! Diagnostics the server reports to whoever runs it, such as the address it
+//! listens on or a tool call that timed out. These are unrelated to the MCP
+//! `notifications/message` log messages a server sends its clients.
+
+use std::sync::Arc;
+
+/// Severity of a diagnostic, named after the `Logger` method it is passed to
+/// on the Ruby side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// Receives the server's diagnostics. The default one discards them.
+#[derive(Clone, Default)]
+pub struct Logger(Option<Arc<dyn Fn(Level, String) + Send + Sync>>);
+
+impl Logger {
+    pub fn new(log: impl Fn(Level, String) + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(log)))
+    }
+
+    pub fn log(&self, level: Level, message: impl Into<String>) {
+        if let Some(log) = &self.0 {
+            log(level, message.into());
+        }
+    }
+
+    pub fn info(&self, message: impl Into<String>) {
+        self.log(Level::Info, message);
+    }
+
+    pub fn warn(&self, message: impl Into<String>) {
+        self.log(Level::Warn, message);
+    }
+
+    pub fn error(&self, message: impl Into<String>) {
+        self.log(Level::Error, message);
+    }
+}
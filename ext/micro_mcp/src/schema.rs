@@ -0,0 +1,797 @@
+//! JSON Schemas registered for tools.
+//!
+//! Schemas are passed to clients exactly as registered, so they are checked
+//! up front: a schema that is not well formed is reported when the tool is
+//! registered rather than by whichever client trips over it. Tool arguments
+//! are then validated against them before any Ruby code runs.
+//!
+//! Validation covers the keywords tools commonly use; `format`, non-local
+//! `$ref`s and the `unevaluated*` keywords are not enforced. Patterns are
+//! compiled with the `regex` crate, which supports the ECMA 262 syntax
+//! schemas are written in except for look-around and backreferences;
+//! patterns using those are rejected as unsupported.
+
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+/// Types a schema's `type` keyword may name.
+const TYPES: &[&str] = &[
+    "array", "boolean", "integer", "null", "number", "object", "string",
+];
+
+/// Keywords whose value is a single subschema.
+const SCHEMA_KEYWORDS: &[&str] = &[
+    "additionalItems",
+    "additionalProperties",
+    "contains",
+    "else",
+    "if",
+    "items",
+    "not",
+    "propertyNames",
+    "then",
+    "unevaluatedItems",
+    "unevaluatedProperties",
+];
+
+/// Keywords whose value maps names to subschemas.
+const SCHEMA_MAP_KEYWORDS: &[&str] = &[
+    "$defs",
+    "definitions",
+    "dependentSchemas",
+    "patternProperties",
+    "properties",
+];
+
+/// Keywords whose value is a non-empty list of subschemas.
+const SCHEMA_LIST_KEYWORDS: &[&str] = &["allOf", "anyOf", "oneOf", "prefixItems"];
+
+/// How many `$ref`s validation follows within one another before giving up
+/// on the schema, which keeps a reference cycle such as `{"$ref": "#"}` from
+/// overflowing the stack. Recursive schemas follow one or two references per
+/// level of the instance, and parsed JSON nests 128 levels at most.
+const MAX_REF_DEPTH: usize = 256;
+
+/// A schema that passed [`object_schema`], with its patterns compiled.
+#[derive(Debug)]
+pub struct Schema {
+    json: JsonValue,
+    /// The compiled `pattern`s and `patternProperties` names, by source.
+    patterns: HashMap<String, Regex>,
+}
+
+impl Schema {
+    /// The schema as registered, apart from a filled in `type`.
+    pub fn json(&self) -> &JsonValue {
+        &self.json
+    }
+}
+
+/// Checks that `schema` is an object schema, as MCP requires of tool input
+/// and output schemas, and returns it. A missing `type` is filled in as
+/// `"object"`; everything else is kept as is. `label`, e.g. `"input"`, names
+/// the schema in errors.
+pub fn object_schema(schema: JsonValue, label: &str) -> Result<Schema, String> {
+    let JsonValue::Object(mut schema) = schema else {
+        return Err(format!("{label} schema must be an object"));
+    };
+    match schema.get("type") {
+        None => {
+            schema.insert("type".to_string(), "object".into());
+        }
+        Some(JsonValue::String(kind)) if kind == "object" => {}
+        Some(kind) => {
+            return Err(format!(
+                "{label} schema must have type \"object\", not {kind}"
+            ))
+        }
+    }
+    let root = JsonValue::Object(schema);
+    let mut patterns = HashMap::new();
+    check(&root, &root, "", &mut patterns).map_err(|e| format!("invalid {label} schema {e}"))?;
+    Ok(Schema {
+        json: root,
+        patterns,
+    })
+}
+
+/// Checks the subschema at `path` and everything below it, compiling its
+/// patterns into `patterns`.
+fn check(
+    root: &JsonValue,
+    schema: &JsonValue,
+    path: &str,
+    patterns: &mut HashMap<String, Regex>,
+) -> Result<(), String> {
+    let schema = match schema {
+        JsonValue::Bool(_) => return Ok(()),
+        JsonValue::Object(schema) => schema,
+        _ => return Err(invalid(path, "expected a schema object or boolean")),
+    };
+
+    for (keyword, value) in schema {
+        let at = format!("{path}/{}", escape(keyword));
+        match keyword.as_str() {
+            "type" => check_type(value, &at)?,
+            "required" => {
+                let names = value
+                    .as_array()
+                    .filter(|names| names.iter().all(JsonValue::is_string));
+                if names.is_none() {
+                    return Err(invalid(&at, "expected an array of strings"));
+                }
+            }
+            "enum" if !value.is_array() => return Err(invalid(&at, "expected an array")),
+            "pattern" => {
+                let pattern = value
+                    .as_str()
+                    .ok_or_else(|| invalid(&at, "expected a string"))?;
+                compile(pattern, &at, patterns)?;
+            }
+            "$ref" => check_ref(root, value, &at)?,
+            // items may also be an array of schemas in older drafts
+            "items" if value.is_array() => check_list(root, value, &at, false, patterns)?,
+            keyword if SCHEMA_KEYWORDS.contains(&keyword) => check(root, value, &at, patterns)?,
+            keyword if SCHEMA_MAP_KEYWORDS.contains(&keyword) => {
+                let Some(schemas) = value.as_object() else {
+                    return Err(invalid(&at, "expected an object"));
+                };
+                for (name, schema) in schemas {
+                    let at = format!("{at}/{}", escape(name));
+                    if keyword == "patternProperties" {
+                        compile(name, &at, patterns)?;
+                    }
+                    check(root, schema, &at, patterns)?;
+                }
+            }
+            keyword if SCHEMA_LIST_KEYWORDS.contains(&keyword) => {
+                check_list(root, value, &at, true, patterns)?
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_type(value: &JsonValue, path: &str) -> Result<(), String> {
+    let known = |kind: &JsonValue| kind.as_str().is_some_and(|kind| TYPES.contains(&kind));
+    let valid = match value {
+        JsonValue::Array(kinds) => kinds.iter().all(known),
+        kind => known(kind),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid(path, &format!("unknown type {value}")))
+    }
+}
+
+fn check_list(
+    root: &JsonValue,
+    value: &JsonValue,
+    path: &str,
+    non_empty: bool,
+    patterns: &mut HashMap<String, Regex>,
+) -> Result<(), String> {
+    match value.as_array() {
+        Some(schemas) if !(non_empty && schemas.is_empty()) => {
+            for (i, schema) in schemas.iter().enumerate() {
+                check(root, schema, &format!("{path}/{i}"), patterns)?;
+            }
+            Ok(())
+        }
+        _ => Err(invalid(path, "expected a non-empty array of schemas")),
+    }
+}
+
+/// Local references must point at a subschema of the registered schema.
+fn check_ref(root: &JsonValue, value: &JsonValue, path: &str) -> Result<(), String> {
+    let Some(reference) = value.as_str() else {
+        return Err(invalid(path, "expected a string"));
+    };
+    if let Some(pointer) = reference.strip_prefix('#') {
+        if root.pointer(pointer).is_none() {
+            return Err(invalid(
+                path,
+                &format!("unresolvable reference {reference}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Compiles `pattern` into `patterns`, unless an earlier keyword did.
+fn compile(pattern: &str, path: &str, patterns: &mut HashMap<String, Regex>) -> Result<(), String> {
+    if !patterns.contains_key(pattern) {
+        let regex = Regex::new(pattern).map_err(|e| {
+            // the last line of a syntax error says what is wrong, the rest
+            // points at where
+            let e = e.to_string();
+            let reason = e.lines().last().unwrap_or_default();
+            let reason = reason.strip_prefix("error: ").unwrap_or(reason);
+            let kind = if reason.ends_with("not supported") {
+                "unsupported"
+            } else {
+                "invalid"
+            };
+            invalid(path, &format!("{kind} pattern {pattern:?}: {reason}"))
+        })?;
+        patterns.insert(pattern.to_string(), regex);
+    }
+    Ok(())
+}
+
+/// Escapes `name` for use in a JSON pointer.
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+fn invalid(path: &str, problem: &str) -> String {
+    let path = if path.is_empty() { "/" } else { path };
+    format!("at {path}: {problem}")
+}
+
+/// Validates `instance` against `root` and returns every violation, each
+/// prefixed with the JSON pointer of the offending value. Fails instead when
+/// the schema cannot be applied, which happens when its references nest more
+/// than [`MAX_REF_DEPTH`] deep.
+pub fn validate(root: &Schema, instance: &JsonValue) -> Result<Vec<String>, String> {
+    let mut validator = Validator::new(root, 0);
+    validator.validate(&root.json, instance, "");
+    match validator.schema_error {
+        Some(error) => Err(error),
+        None => Ok(validator.errors),
+    }
+}
+
+struct Validator<'a> {
+    root: &'a Schema,
+    errors: Vec<String>,
+    /// How many references the current subschema was reached through.
+    depth: usize,
+    schema_error: Option<String>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(root: &'a Schema, depth: usize) -> Self {
+        Self {
+            root,
+            errors: Vec::new(),
+            depth,
+            schema_error: None,
+        }
+    }
+
+    fn error(&mut self, path: &str, problem: String) {
+        let path = if path.is_empty() { "/" } else { path };
+        self.errors.push(format!("{path}: {problem}"));
+    }
+
+    /// Whether `instance` is valid against `schema`, without reporting why.
+    fn is_valid(&mut self, schema: &JsonValue, instance: &JsonValue) -> bool {
+        let mut validator = Validator::new(self.root, self.depth);
+        validator.validate(schema, instance, "");
+        if validator.schema_error.is_some() {
+            self.schema_error = validator.schema_error;
+        }
+        validator.errors.is_empty()
+    }
+
+    fn validate(&mut self, schema: &JsonValue, instance: &JsonValue, path: &str) {
+        if self.schema_error.is_some() {
+            return;
+        }
+        let schema = match schema {
+            JsonValue::Bool(true) => return,
+            JsonValue::Bool(false) => return self.error(path, "is not allowed".to_string()),
+            JsonValue::Object(schema) => schema,
+            _ => return,
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(JsonValue::as_str) {
+            if let Some(target) = reference
+                .strip_prefix('#')
+                .and_then(|pointer| self.root.json.pointer(pointer))
+            {
+                if self.depth == MAX_REF_DEPTH {
+                    self.schema_error = Some(format!(
+                        "references nest more than {MAX_REF_DEPTH} deep at {reference}"
+                    ));
+                    return;
+                }
+                self.depth += 1;
+                self.validate(target, instance, path);
+                self.depth -= 1;
+            }
+        }
+        if let Some(kind) = schema.get("type") {
+            let kinds: Vec<&str> = match kind {
+                JsonValue::Array(kinds) => kinds.iter().filter_map(JsonValue::as_str).collect(),
+                kind => kind.as_str().into_iter().collect(),
+            };
+            if !kinds.iter().any(|kind| has_type(instance, kind)) {
+                let problem = format!("expected {}, got {}", kinds.join(" or "), type_of(instance));
+                return self.error(path, problem);
+            }
+        }
+        if let Some(values) = schema.get("enum").and_then(JsonValue::as_array) {
+            if !values.iter().any(|value| equal(value, instance)) {
+                self.error(
+                    path,
+                    format!("must be one of {}", JsonValue::from(values.clone())),
+                );
+            }
+        }
+        if let Some(value) = schema.get("const") {
+            if !equal(value, instance) {
+                self.error(path, format!("must be {value}"));
+            }
+        }
+
+        match instance {
+            JsonValue::Number(number) => {
+                self.validate_number(schema, number.as_f64().unwrap(), path)
+            }
+            JsonValue::String(string) => self.validate_string(schema, string, path),
+            JsonValue::Array(items) => self.validate_array(schema, items, path),
+            JsonValue::Object(object) => self.validate_object(schema, object, path),
+            _ => {}
+        }
+        self.validate_combinators(schema, instance, path);
+    }
+
+    fn validate_number(&mut self, schema: &JsonMap<String, JsonValue>, number: f64, path: &str) {
+        let limit = |keyword: &str| schema.get(keyword).and_then(JsonValue::as_f64);
+        if let Some(minimum) = limit("minimum").filter(|minimum| number < *minimum) {
+            self.error(path, format!("must be at least {minimum}"));
+        }
+        if let Some(maximum) = limit("maximum").filter(|maximum| number > *maximum) {
+            self.error(path, format!("must be at most {maximum}"));
+        }
+        if let Some(minimum) = limit("exclusiveMinimum").filter(|minimum| number <= *minimum) {
+            self.error(path, format!("must be greater than {minimum}"));
+        }
+        if let Some(maximum) = limit("exclusiveMaximum").filter(|maximum| number >= *maximum) {
+            self.error(path, format!("must be less than {maximum}"));
+        }
+        if let Some(factor) = limit("multipleOf").filter(|factor| *factor > 0.0) {
+            let quotient = number / factor;
+            if (quotient - quotient.round()).abs() > 1e-9 {
+                self.error(path, format!("must be a multiple of {factor}"));
+            }
+        }
+    }
+
+    fn validate_string(&mut self, schema: &JsonMap<String, JsonValue>, string: &str, path: &str) {
+        let length = string.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(JsonValue::as_u64) {
+            if length < min {
+                self.error(path, format!("must be at least {min} characters long"));
+            }
+        }
+        if let Some(max) = schema.get("maxLength").and_then(JsonValue::as_u64) {
+            if length > max {
+                self.error(path, format!("must be at most {max} characters long"));
+            }
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(JsonValue::as_str) {
+            let regex = self.root.patterns.get(pattern);
+            if regex.is_some_and(|regex| !regex.is_match(string)) {
+                self.error(path, format!("must match the pattern {pattern}"));
+            }
+        }
+    }
+
+    fn validate_array(
+        &mut self,
+        schema: &JsonMap<String, JsonValue>,
+        items: &[JsonValue],
+        path: &str,
+    ) {
+        let len = items.len() as u64;
+        if let Some(min) = schema.get("minItems").and_then(JsonValue::as_u64) {
+            if len < min {
+                self.error(path, format!("must have at least {min} items"));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(JsonValue::as_u64) {
+            if len > max {
+                self.error(path, format!("must have at most {max} items"));
+            }
+        }
+        if schema.get("uniqueItems") == Some(&JsonValue::Bool(true)) {
+            let duplicate = items
+                .iter()
+                .enumerate()
+                .any(|(i, item)| items[..i].iter().any(|other| equal(item, other)));
+            if duplicate {
+                self.error(path, "must not contain duplicate items".to_string());
+            }
+        }
+
+        // Tuple schemas come from `prefixItems`, or from an `items` array in
+        // older drafts, with `items` or `additionalItems` covering the rest.
+        let (prefix, rest) = match (schema.get("prefixItems"), schema.get("items")) {
+            (Some(JsonValue::Array(prefix)), rest) => (prefix.as_slice(), rest),
+            (_, Some(JsonValue::Array(prefix))) => {
+                (prefix.as_slice(), schema.get("additionalItems"))
+            }
+            (_, rest) => (&[][..], rest),
+        };
+        for (i, item) in items.iter().enumerate() {
+            let item_schema = match prefix.get(i) {
+                Some(schema) => schema,
+                None => match rest {
+                    Some(schema) => schema,
+                    None => break,
+                },
+            };
+            self.validate(item_schema, item, &format!("{path}/{i}"));
+        }
+
+        if let Some(contains) = schema.get("contains") {
+            if !items.iter().any(|item| self.is_valid(contains, item)) {
+                self.error(path, "must contain a matching item".to_string());
+            }
+        }
+    }
+
+    fn validate_object(
+        &mut self,
+        schema: &JsonMap<String, JsonValue>,
+        object: &JsonMap<String, JsonValue>,
+        path: &str,
+    ) {
+        if let Some(required) = schema.get("required").and_then(JsonValue::as_array) {
+            for name in required.iter().filter_map(JsonValue::as_str) {
+                if !object.contains_key(name) {
+                    self.error(path, format!("missing required property {name:?}"));
+                }
+            }
+        }
+        if let Some(dependencies) = schema
+            .get("dependentRequired")
+            .and_then(JsonValue::as_object)
+        {
+            for (name, required) in dependencies {
+                if !object.contains_key(name) {
+                    continue;
+                }
+                for other in required
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(JsonValue::as_str)
+                {
+                    if !object.contains_key(other) {
+                        self.error(
+                            path,
+                            format!("property {other:?} is required with {name:?}"),
+                        );
+                    }
+                }
+            }
+        }
+        let len = object.len() as u64;
+        if let Some(min) = schema.get("minProperties").and_then(JsonValue::as_u64) {
+            if len < min {
+                self.error(path, format!("must have at least {min} properties"));
+            }
+        }
+        if let Some(max) = schema.get("maxProperties").and_then(JsonValue::as_u64) {
+            if len > max {
+                self.error(path, format!("must have at most {max} properties"));
+            }
+        }
+
+        let properties = schema.get("properties").and_then(JsonValue::as_object);
+        let root = self.root;
+        let patterns: Vec<(&Regex, &JsonValue)> = schema
+            .get("patternProperties")
+            .and_then(JsonValue::as_object)
+            .into_iter()
+            .flatten()
+            .filter_map(|(pattern, schema)| Some((root.patterns.get(pattern)?, schema)))
+            .collect();
+        for (name, value) in object {
+            let at = format!("{path}/{}", escape(name));
+            if let Some(names) = schema.get("propertyNames") {
+                if !self.is_valid(names, &JsonValue::String(name.clone())) {
+                    self.error(&at, "is not an allowed property name".to_string());
+                }
+            }
+            let mut matched = false;
+            if let Some(property) = properties.and_then(|properties| properties.get(name)) {
+                matched = true;
+                self.validate(property, value, &at);
+            }
+            for (regex, property) in &patterns {
+                if regex.is_match(name) {
+                    matched = true;
+                    self.validate(property, value, &at);
+                }
+            }
+            match schema.get("additionalProperties") {
+                Some(JsonValue::Bool(false)) if !matched => {
+                    self.error(&at, "is not an allowed property".to_string())
+                }
+                Some(additional) if !matched => self.validate(additional, value, &at),
+                _ => {}
+            }
+        }
+    }
+
+    fn validate_combinators(
+        &mut self,
+        schema: &JsonMap<String, JsonValue>,
+        instance: &JsonValue,
+        path: &str,
+    ) {
+        if let Some(schemas) = schema.get("allOf").and_then(JsonValue::as_array) {
+            for schema in schemas {
+                self.validate(schema, instance, path);
+            }
+        }
+        if let Some(schemas) = schema.get("anyOf").and_then(JsonValue::as_array) {
+            if !schemas.iter().any(|schema| self.is_valid(schema, instance)) {
+                self.error(path, "must match at least one schema in anyOf".to_string());
+            }
+        }
+        if let Some(schemas) = schema.get("oneOf").and_then(JsonValue::as_array) {
+            let matches = schemas
+                .iter()
+                .filter(|schema| self.is_valid(schema, instance))
+                .count();
+            if matches != 1 {
+                self.error(
+                    path,
+                    format!("must match exactly one schema in oneOf, matched {matches}"),
+                );
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            if self.is_valid(not, instance) {
+                self.error(path, "must not match the schema in not".to_string());
+            }
+        }
+        if let Some(condition) = schema.get("if") {
+            let branch = if self.is_valid(condition, instance) {
+                schema.get("then")
+            } else {
+                schema.get("else")
+            };
+            if let Some(branch) = branch {
+                self.validate(branch, instance, path);
+            }
+        }
+    }
+}
+
+fn has_type(instance: &JsonValue, kind: &str) -> bool {
+    match kind {
+        "integer" => instance
+            .as_f64()
+            .is_some_and(|number| number.fract() == 0.0),
+        kind => type_of(instance) == kind || (kind == "number" && instance.is_number()),
+    }
+}
+
+fn type_of(instance: &JsonValue) -> &'static str {
+    match instance {
+        JsonValue::Null => "null",
+        JsonValue::Bool(_) => "boolean",
+        JsonValue::Number(number) if number.is_f64() => "number",
+        JsonValue::Number(_) => "integer",
+        JsonValue::String(_) => "string",
+        JsonValue::Array(_) => "array",
+        JsonValue::Object(_) => "object",
+    }
+}
+
+/// JSON equality, under which `1` and `1.0` are the same number.
+fn equal(a: &JsonValue, b: &JsonValue) -> bool {
+    match (a, b) {
+        (JsonValue::Number(a), JsonValue::Number(b)) => a.as_f64() == b.as_f64(),
+        (JsonValue::Array(a), JsonValue::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(a, b))
+        }
+        (JsonValue::Object(a), JsonValue::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(name, value)| b.get(name).is_some_and(|other| equal(value, other)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{object_schema, validate, MAX_REF_DEPTH};
+    use serde_json::{json, Value as JsonValue};
+
+    fn errors(schema: JsonValue, instance: JsonValue) -> Vec<String> {
+        let schema = object_schema(schema, "input").unwrap();
+        validate(&schema, &instance).unwrap()
+    }
+
+    /// Validates a property `value` against the property schema `schema`.
+    fn property_errors(schema: JsonValue, value: JsonValue) -> Vec<String> {
+        errors(
+            json!({"properties": {"value": schema}}),
+            json!({ "value": value }),
+        )
+    }
+
+    #[test]
+    fn object_schemas_are_required() {
+        assert_eq!(
+            object_schema(json!({}), "input").unwrap().json(),
+            &json!({"type": "object"})
+        );
+        assert_eq!(
+            object_schema(json!([]), "input").unwrap_err(),
+            "input schema must be an object"
+        );
+        assert_eq!(
+            object_schema(json!({"type": "string"}), "output").unwrap_err(),
+            "output schema must have type \"object\", not \"string\""
+        );
+        assert_eq!(
+            object_schema(json!({"properties": {"a": {"type": "text"}}}), "input").unwrap_err(),
+            "invalid input schema at /properties/a/type: unknown type \"text\""
+        );
+        assert_eq!(
+            object_schema(json!({"properties": {"a": {"$ref": "#/$defs/a"}}}), "input")
+                .unwrap_err(),
+            "invalid input schema at /properties/a/$ref: unresolvable reference #/$defs/a"
+        );
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected_at_registration() {
+        let error = object_schema(
+            json!({"properties": {"code": {"type": "string", "pattern": "[a-z"}}}),
+            "input",
+        )
+        .unwrap_err();
+        assert!(
+            error.starts_with(
+                "invalid input schema at /properties/code/pattern: invalid pattern \"[a-z\""
+            ),
+            "{error}"
+        );
+
+        let error =
+            object_schema(json!({"patternProperties": {"(?=x)": {}}}), "input").unwrap_err();
+        assert!(
+            error.starts_with(
+                "invalid input schema at /patternProperties/(?=x): unsupported pattern"
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn patterns_apply_to_values_and_property_names() {
+        let schema = json!({
+            "properties": {"code": {"type": "string", "pattern": "^[A-Z]{3}$"}},
+            "patternProperties": {"^x-": {"type": "integer"}},
+            "additionalProperties": false
+        });
+        assert_eq!(
+            errors(schema.clone(), json!({"code": "ABC", "x-count": 2})),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            errors(schema, json!({"code": "abc", "x-count": "2", "other": 1})),
+            vec![
+                "/code: must match the pattern ^[A-Z]{3}$",
+                "/other: is not an allowed property",
+                "/x-count: expected integer, got string",
+            ]
+        );
+    }
+
+    #[test]
+    fn reference_cycles_stop_at_the_depth_limit() {
+        let schema = object_schema(
+            json!({
+                "$defs": {"loop": {"$ref": "#/$defs/loop"}},
+                "properties": {"value": {"$ref": "#/$defs/loop"}}
+            }),
+            "input",
+        )
+        .unwrap();
+        assert_eq!(
+            validate(&schema, &json!({"value": 1})).unwrap_err(),
+            format!("references nest more than {MAX_REF_DEPTH} deep at #/$defs/loop")
+        );
+        // the cycle is only followed for values that reach it
+        assert_eq!(validate(&schema, &json!({})).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn recursive_schemas_validate_nested_values() {
+        let schema = json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {"children": {"type": "array", "items": {"$ref": "#/$defs/node"}}},
+                    "required": ["name"]
+                }
+            },
+            "properties": {"root": {"$ref": "#/$defs/node"}}
+        });
+        assert_eq!(
+            errors(
+                schema,
+                json!({"root": {"name": "a", "children": [{"name": "b"}, {"children": []}]}})
+            ),
+            vec!["/root/children/1: missing required property \"name\""]
+        );
+    }
+
+    #[test]
+    fn errors_point_at_nested_values() {
+        let schema = json!({
+            "properties": {
+                "user": {
+                    "type": "object",
+                    "properties": {
+                        "tags": {"type": "array", "items": {"type": "string"}},
+                        "a/b": {"type": "boolean"}
+                    },
+                    "required": ["name"],
+                    "additionalProperties": false
+                }
+            },
+            "required": ["user", "id"]
+        });
+        assert_eq!(
+            errors(
+                schema,
+                json!({"user": {"tags": ["x", 1], "a/b": null, "extra": true}})
+            ),
+            vec![
+                "/: missing required property \"id\"",
+                "/user: missing required property \"name\"",
+                "/user/a~1b: expected boolean, got null",
+                "/user/extra: is not an allowed property",
+                "/user/tags/1: expected string, got integer",
+            ]
+        );
+    }
+
+    #[test]
+    fn integers_are_numbers_without_a_fraction() {
+        assert!(property_errors(json!({"type": "integer"}), json!(3)).is_empty());
+        assert!(property_errors(json!({"type": "integer"}), json!(3.0)).is_empty());
+        assert_eq!(
+            property_errors(json!({"type": "integer"}), json!(3.5)),
+            vec!["/value: expected integer, got number"]
+        );
+        assert!(property_errors(json!({"type": "number"}), json!(3)).is_empty());
+        assert!(property_errors(json!({"type": "number"}), json!(3.5)).is_empty());
+        assert_eq!(
+            property_errors(json!({"type": "number"}), json!("3")),
+            vec!["/value: expected number, got string"]
+        );
+        assert!(property_errors(json!({"type": ["integer", "null"]}), json!(null)).is_empty());
+    }
+
+    #[test]
+    fn enum_and_const_compare_numbers_by_value() {
+        assert!(property_errors(json!({"enum": [1, "one"]}), json!(1.0)).is_empty());
+        assert_eq!(
+            property_errors(json!({"enum": [1, "one"]}), json!(2)),
+            vec!["/value: must be one of [1,\"one\"]"]
+        );
+        assert!(property_errors(json!({"const": {"a": [1]}}), json!({"a": [1.0]})).is_empty());
+        assert_eq!(
+            property_errors(json!({"const": "x"}), json!("y")),
+            vec!["/value: must be \"x\""]
+        );
+    }
+}
@@ -1,38 +1,62 @@
 use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use indexmap::{map::Slice, IndexMap};
 use rust_mcp_sdk::{
-    mcp_server::{server_runtime, ServerHandler, ServerRuntime},
+    mcp_server::ServerHandler,
     schema::{
-        schema_utils::CallToolError, CallToolRequest, CallToolResult, GetPromptRequest,
-        GetPromptResult, Implementation, InitializeResult, ListPromptsRequest, ListPromptsResult,
-        ListToolsRequest, ListToolsResult, Prompt, PromptArgument, PromptMessage, RpcError,
-        ServerCapabilities, ServerCapabilitiesPrompts, ServerCapabilitiesTools, TextContent, Tool,
-        ToolInputSchema, LATEST_PROTOCOL_VERSION,
+        schema_utils::{CallToolError, NotificationFromServer},
+        BlobResourceContents, CallToolRequest, CallToolResult, ContentBlock, CreateMessageRequest,
+        CreateMessageRequestParams, CreateMessageResult, GetPromptRequest, GetPromptResult,
+        Implementation, InitializeResult, ListPromptsRequest, ListPromptsResult,
+        ListResourceTemplatesRequest, ListResourceTemplatesResult, ListResourcesRequest,
+        ListResourcesResult, ListToolsRequest, ProgressNotification, ProgressNotificationParams,
+        ProgressToken, Prompt, PromptArgument, PromptListChangedNotification, PromptMessage,
+        ReadResourceRequest, ReadResourceResult, ReadResourceResultContentsItem, RequestId,
+        Resource, ResourceListChangedNotification, ResourceTemplate, ResourceUpdatedNotification,
+        ResourceUpdatedNotificationParams, Result as McpResult, RpcError, ServerCapabilities,
+        ServerCapabilitiesPrompts, ServerCapabilitiesResources, ServerCapabilitiesTools,
+        SubscribeRequest, TextContent, TextResourceContents, Tool, ToolAnnotations,
+        ToolInputSchema, ToolListChangedNotification, UnsubscribeRequest, LATEST_PROTOCOL_VERSION,
     },
-    McpServer, StdioTransport, TransportOptions,
+    McpServer,
 };
+use serde::Deserialize;
 use serde_json::{Map as JsonMap, Value as JsonValue};
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{oneshot, watch, Notify};
 
 use magnus::{
     block::Proc,
-    value::{BoxValue, ReprValue},
-    Error, Ruby, Value,
+    encoding::EncodingCapable,
+    error::ErrorType,
+    scan_args::{get_kwargs, scan_args},
+    value::{BoxValue, Opaque, ReprValue},
+    Error, IntoValue, RArray, RHash, RString, Ruby, Thread, TryConvert, Value,
 };
 use magnus::{typed_data::DataTypeFunctions, TypedData};
-use std::cell::RefCell;
-use std::rc::Rc;
 
-use crate::utils::nogvl;
+use crate::config::{ServerConfig, TransportKind};
+use crate::content;
+use crate::dispatcher::{Dispatcher, Job};
+use crate::errors::{ServeError, StopReason, Stopped};
+use crate::logging::{Level, Logger};
+use crate::schema::{self, Schema};
+use crate::session::{self, Cancellation, Peer, SessionHandler, SessionRuntime};
+use crate::signals::{self, Signals};
+use crate::uri_template::UriTemplate;
+use crate::utils::{self, nogvl, with_gvl};
 
 static RUNTIME: OnceLock<Runtime> = OnceLock::new();
-static SHUTDOWN_FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
-
-fn shutdown_flag() -> &'static Arc<AtomicBool> {
-    SHUTDOWN_FLAG.get_or_init(|| Arc::new(AtomicBool::new(false)))
-}
+static DEFAULT_SERVER: OnceLock<Arc<ServerState>> = OnceLock::new();
 
 type ToolHandler = RubyHandler;
 
@@ -51,25 +75,213 @@ unsafe impl Sync for RubyHandler {}
 #[derive(Clone)]
 struct ToolEntry {
     tool: Tool,
+    /// The input schema as registered; `tool.input_schema` cannot represent
+    /// most of it.
+    input_schema: Arc<Schema>,
+    output_schema: Option<Arc<Schema>>,
+    /// Overrides the server's `tool_timeout` for this tool.
+    timeout: Option<Duration>,
     handler: ToolHandler,
+    /// Number given when first registered, see [`Listed`].
+    seq: u64,
 }
 
-static TOOLS: OnceLock<Mutex<HashMap<String, ToolEntry>>> = OnceLock::new();
+impl ToolEntry {
+    /// The tool as listed to clients.
+    fn to_json(&self) -> Result<JsonValue, serde_json::Error> {
+        let mut tool = serde_json::to_value(&self.tool)?;
+        tool["inputSchema"] = self.input_schema.json().clone();
+        if let Some(output_schema) = &self.output_schema {
+            tool["outputSchema"] = output_schema.json().clone();
+        }
+        Ok(tool)
+    }
+}
+
+/// Optional fields of a tool besides its schemas, passed by Ruby as a Hash
+/// using the MCP field names.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ToolDetails {
+    title: Option<String>,
+    description: Option<String>,
+    annotations: Option<ToolAnnotations>,
+    #[serde(rename = "_meta")]
+    meta: Option<JsonMap<String, JsonValue>>,
+    /// Seconds a call may run; not sent to clients.
+    timeout: Option<f64>,
+}
 
 #[derive(Clone)]
 struct PromptEntry {
     prompt: Prompt,
     handler: RubyHandler,
+    /// Number given when first registered, see [`Listed`].
+    seq: u64,
+}
+
+#[derive(Clone)]
+struct ResourceEntry {
+    resource: Resource,
+    handler: RubyHandler,
+    /// Number given when first registered, see [`Listed`].
+    seq: u64,
+}
+
+struct ResourceTemplateEntry {
+    template: ResourceTemplate,
+    /// `template.uri_template` parsed for matching.
+    uri_template: UriTemplate,
+    handler: RubyHandler,
+    /// Number given when first registered, see [`Listed`].
+    seq: u64,
+}
+
+/// An entry of a registry that is listed a page at a time.
+trait Listed {
+    /// Number the entry was given when first registered. Numbers increase in
+    /// the order entries are listed and are never reused, so a cursor can
+    /// refer to one whose entry has since been removed.
+    fn seq(&self) -> u64;
+}
+
+impl Listed for ToolEntry {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Listed for PromptEntry {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Listed for ResourceEntry {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Listed for ResourceTemplateEntry {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+/// The proc serving a resource, its MIME type, and the variables of its URI
+/// when it is served by a resource template.
+type ResourceProc = (Proc, Option<String>, Option<JsonMap<String, JsonValue>>);
+
+/// JSON-RPC error code for reading a resource that does not exist.
+const RESOURCE_NOT_FOUND: i64 = -32002;
+
+fn resource_not_found(uri: String) -> RpcError {
+    RpcError {
+        code: RESOURCE_NOT_FOUND,
+        data: Some(serde_json::json!({ "uri": uri })),
+        message: "Resource not found".to_string(),
+    }
 }
 
-static PROMPTS: OnceLock<Mutex<HashMap<String, PromptEntry>>> = OnceLock::new();
+/// Registries and shutdown state owned by one MCP server.
+///
+/// The module level `MicroMcpNative` functions operate on a process wide
+/// default instance, while `MicroMcpNative::Server` objects each own a
+/// separate one.
+#[derive(Default)]
+pub struct ServerState {
+    /// Tools, prompts and resources in the order they were first registered,
+    /// which is the order they are listed in. Resources are keyed by URI.
+    tools: Mutex<IndexMap<String, ToolEntry>>,
+    prompts: Mutex<IndexMap<String, PromptEntry>>,
+    resources: Mutex<IndexMap<String, ResourceEntry>>,
+    /// Resource templates keyed by URI template.
+    resource_templates: Mutex<IndexMap<String, ResourceTemplateEntry>>,
+    /// Number of entries registered so far, across all registries.
+    registrations: AtomicU64,
+    /// Notified whenever a registry changes, so sessions can tell their
+    /// clients to list it again.
+    tools_changed: watch::Sender<()>,
+    prompts_changed: watch::Sender<()>,
+    resources_changed: watch::Sender<()>,
+    /// URIs of resources marked as updated, for the sessions subscribed to
+    /// them.
+    resource_updates: ResourceUpdates,
+    shutdown: watch::Sender<Option<ShutdownRequest>>,
+    running: AtomicBool,
+    /// Number of runs begun so far, which identifies the current one.
+    runs: AtomicU64,
+    /// Set once shutdown has started; new tool calls are refused from then on.
+    draining: AtomicBool,
+    calls: Mutex<InFlightCalls>,
+    calls_changed: Notify,
+    /// Called on SIGHUP instead of stopping the server.
+    reload_handler: Mutex<Option<RubyHandler>>,
+    dispatcher: Dispatcher,
+}
+
+/// Number of resource updates a session can fall behind before it misses
+/// some.
+const RESOURCE_UPDATES_CAPACITY: usize = 1024;
 
-fn tools() -> &'static Mutex<HashMap<String, ToolEntry>> {
-    TOOLS.get_or_init(|| Mutex::new(HashMap::new()))
+struct ResourceUpdates(broadcast::Sender<String>);
+
+impl Default for ResourceUpdates {
+    fn default() -> Self {
+        Self(broadcast::channel(RESOURCE_UPDATES_CAPACITY).0)
+    }
 }
 
-fn prompts() -> &'static Mutex<HashMap<String, PromptEntry>> {
-    PROMPTS.get_or_init(|| Mutex::new(HashMap::new()))
+#[derive(Clone, Copy)]
+struct ShutdownRequest {
+    /// Overrides the configured grace period for this shutdown.
+    grace: Option<Duration>,
+}
+
+#[derive(Default)]
+struct InFlightCalls {
+    next_id: u64,
+    calls: HashMap<u64, InFlightCall>,
+}
+
+/// A tool, prompt or resource call queued on the dispatcher.
+struct InFlightCall {
+    /// Name of the tool, or `prompt:` followed by the name of the prompt, or
+    /// `resource:` followed by the URI of the resource.
+    name: String,
+    /// The pool thread running the call, once it has started.
+    thread: Option<Opaque<Thread>>,
+}
+
+/// Time a killed call gets to unwind before it is answered without waiting
+/// for it any longer.
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// How a call queued with `dispatch_call` ended.
+enum CallEnd<T> {
+    Finished(Result<T, String>),
+    /// Aborted on shutdown.
+    Aborted,
+    TimedOut(Duration),
+    Cancelled,
+}
+
+/// Invalidates a call's runtime object once the handler is done with it.
+struct CallGuard {
+    runtime: RubyMcpServer,
+    /// Identifies the call among the in-flight calls.
+    id: u64,
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        self.runtime.invalidate();
+    }
+}
+
+fn default_server() -> &'static Arc<ServerState> {
+    DEFAULT_SERVER.get_or_init(Arc::default)
 }
 
 fn ruby_value_to_json_value(ruby: &Ruby, val: Value) -> Result<JsonValue, Error> {
@@ -78,7 +290,7 @@ fn ruby_value_to_json_value(ruby: &Ruby, val: Value) -> Result<JsonValue, Error>
         .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))
 }
 
-fn json_value_to_ruby_value(ruby: &Ruby, val: &JsonValue) -> Result<Value, Error> {
+pub fn json_value_to_ruby_value(ruby: &Ruby, val: &JsonValue) -> Result<Value, Error> {
     let json_str = serde_json::to_string(val)
         .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))?;
     Ok(magnus::eval!(
@@ -88,37 +300,6 @@ fn json_value_to_ruby_value(ruby: &Ruby, val: &JsonValue) -> Result<Value, Error
     )?)
 }
 
-fn parse_tool_input_schema(json: JsonValue) -> ToolInputSchema {
-    if let JsonValue::Object(obj) = json {
-        let required = obj
-            .get("required")
-            .and_then(|v| v.as_array())
-            .map(|arr| {
-                arr.iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-
-        let properties = obj
-            .get("properties")
-            .and_then(|v| v.as_object())
-            .map(|props| {
-                props
-                    .iter()
-                    .filter_map(|(k, v)| match v {
-                        JsonValue::Object(map) => Some((k.clone(), map.clone())),
-                        _ => None,
-                    })
-                    .collect::<HashMap<String, JsonMap<String, JsonValue>>>()
-            });
-
-        ToolInputSchema::new(required, properties)
-    } else {
-        ToolInputSchema::new(Vec::new(), None)
-    }
-}
-
 fn parse_prompt_arguments(json: JsonValue) -> Vec<PromptArgument> {
     match json {
         JsonValue::Array(items) => items
@@ -150,30 +331,41 @@ fn parse_prompt_arguments(json: JsonValue) -> Vec<PromptArgument> {
 }
 
 #[derive(Clone, TypedData)]
-#[magnus(class = "MicroMcp::Runtime", free_immediately, unsafe_generics)]
-pub struct RubyMcpServer<'a> {
-    inner: Rc<RefCell<Option<&'a dyn McpServer>>>,
+#[magnus(class = "MicroMcp::Runtime", free_immediately)]
+pub struct RubyMcpServer {
+    /// The session the call came from, until the call has returned.
+    inner: Arc<Mutex<Option<Arc<Peer>>>>,
+    /// The client request the call answers, which requests the call sends to
+    /// the client are tied to.
+    request: Option<RequestId>,
+    /// Token the client asked progress notifications to carry, if any.
+    progress_token: Option<ProgressToken>,
+    cancellation: Cancellation,
 }
 
-impl<'a> DataTypeFunctions for RubyMcpServer<'a> {}
-
-// SAFETY: the wrapped reference is only used while valid
-unsafe impl<'a> Send for RubyMcpServer<'a> {}
+impl DataTypeFunctions for RubyMcpServer {}
 
-impl<'a> RubyMcpServer<'a> {
-    fn new(runtime: &'a dyn McpServer) -> Self {
+impl RubyMcpServer {
+    fn new(
+        runtime: Arc<Peer>,
+        progress_token: Option<ProgressToken>,
+        cancellation: Cancellation,
+    ) -> Self {
         Self {
-            inner: Rc::new(RefCell::new(Some(runtime))),
+            inner: Arc::new(Mutex::new(Some(runtime))),
+            request: session::current_request(),
+            progress_token,
+            cancellation,
         }
     }
 
     fn invalidate(&self) {
-        *self.inner.borrow_mut() = None;
+        *self.inner.lock().unwrap() = None;
     }
 
-    fn runtime(&self) -> Result<&'a dyn McpServer, Error> {
-        match *self.inner.borrow() {
-            Some(ptr) => Ok(ptr),
+    fn runtime(&self) -> Result<Arc<Peer>, Error> {
+        match &*self.inner.lock().unwrap() {
+            Some(peer) => Ok(peer.clone()),
             None => {
                 let ruby = Ruby::get().unwrap();
                 Err(Error::new(
@@ -184,331 +376,1729 @@ impl<'a> RubyMcpServer<'a> {
         }
     }
 
-    pub fn is_initialized(&self) -> Result<bool, Error> {
-        Ok(self.runtime()?.is_initialized())
+    /// Whether the client cancelled the call, which should then stop early.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    pub fn is_initialized(&self) -> Result<bool, Error> {
+        Ok(self.runtime()?.is_initialized())
+    }
+
+    pub fn client_supports_sampling(&self) -> Result<Option<bool>, Error> {
+        Ok(self.runtime()?.client_supports_sampling())
+    }
+
+    pub fn create_message(&self, params: Value) -> Result<Value, Error> {
+        let ruby = Ruby::get().unwrap();
+        let runtime = self.runtime()?;
+        let json_value = ruby_value_to_json_value(&ruby, params)?;
+        let request_params: CreateMessageRequestParams = serde_json::from_value(json_value)
+            .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))?;
+
+        let request = CreateMessageRequest::new(request_params);
+        let result: CreateMessageResult =
+            block_on(runtime.request_for(self.request.clone(), request.into(), None))?
+                .and_then(|result| Ok(result.try_into()?))
+                .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))?;
+
+        let json_result = serde_json::to_value(result)
+            .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))?;
+        json_value_to_ruby_value(&ruby, &json_result)
+    }
+
+    /// `report_progress(progress, total: nil, message: nil)` sends a
+    /// progress notification for the call, or does nothing when the client
+    /// did not ask for progress.
+    pub fn report_progress(&self, args: &[Value]) -> Result<(), Error> {
+        let args = scan_args::<(f64,), (), (), (), _, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<Option<f64>>, Option<Option<String>>), ()>(
+            args.keywords,
+            &[],
+            &["total", "message"],
+        )?;
+        let (progress,) = args.required;
+        let (total, message) = kwargs.optional;
+        let runtime = self.runtime()?;
+        let Some(progress_token) = self.progress_token.clone() else {
+            return Ok(());
+        };
+        let notification = ProgressNotification::new(ProgressNotificationParams {
+            message: message.flatten(),
+            progress,
+            progress_token,
+            total: total.flatten(),
+        });
+        block_on(runtime.send_notification(notification.into()))?.map_err(|e| {
+            let ruby = Ruby::get().unwrap();
+            Error::new(ruby.exception_runtime_error(), e.to_string())
+        })
+    }
+}
+
+/// Runs `future` on the Tokio runtime and waits for it. Other Ruby threads
+/// keep running meanwhile when called from a tool thread, which can be
+/// interrupted, e.g. when the call times out, and then returns the error the
+/// interrupt raised.
+fn block_on<T>(future: impl Future<Output = T>) -> Result<T, Error> {
+    let handle = RUNTIME.get().expect("Tokio not initialised").handle();
+    if tokio::runtime::Handle::try_current().is_ok() {
+        Ok(tokio::task::block_in_place(|| handle.block_on(future)))
+    } else {
+        utils::block_on(handle, future)
+    }
+}
+
+/// What a tool proc returned.
+enum ToolOutput {
+    Text(String),
+    /// One or more `MicroMcp::Content` blocks.
+    Content(Vec<ContentBlock>),
+    /// A Hash, or an Array wrapped as `{"result": [...]}` since structured
+    /// content must be an object.
+    Structured(JsonMap<String, JsonValue>),
+    /// A `MicroMcp::ErrorResult`.
+    Error(Vec<ContentBlock>),
+    /// An exception the tool raised.
+    Exception(ToolException),
+}
+
+/// Number of backtrace lines kept in error results.
+const BACKTRACE_LINES: usize = 10;
+
+/// An exception raised by a tool, as reported to the client.
+struct ToolException {
+    class: String,
+    message: String,
+    backtrace: Option<Vec<String>>,
+}
+
+impl ToolException {
+    /// Describes `error`, or returns it when it is not an exception, such as
+    /// the thread being killed.
+    fn from_error(error: Error, backtraces: bool) -> Result<Self, Error> {
+        match error.error_type() {
+            ErrorType::Jump(_) => Err(error),
+            ErrorType::Error(class, message) => Ok(Self {
+                class: class.inspect(),
+                message: message.to_string(),
+                backtrace: None,
+            }),
+            ErrorType::Exception(exception) => {
+                let backtrace = if backtraces {
+                    exception
+                        .funcall::<_, _, Option<Vec<String>>>("backtrace", ())?
+                        .map(|lines| lines.into_iter().take(BACKTRACE_LINES).collect())
+                } else {
+                    None
+                };
+                Ok(Self {
+                    class: exception.class().inspect(),
+                    message: exception.funcall("message", ())?,
+                    backtrace,
+                })
+            }
+        }
+    }
+
+    fn to_result(&self) -> CallToolResult {
+        let mut text = format!("{}: {}", self.class, self.message);
+        for line in self.backtrace.iter().flatten() {
+            text.push_str("\n    ");
+            text.push_str(line);
+        }
+        let mut details = serde_json::json!({
+            "class": self.class,
+            "message": self.message,
+        });
+        if let Some(backtrace) = &self.backtrace {
+            details["backtrace"] = backtrace.clone().into();
+        }
+        CallToolResult {
+            content: vec![TextContent::new(text, None, None).into()],
+            is_error: Some(true),
+            meta: Some(JsonMap::from_iter([("exception".to_string(), details)])),
+            structured_content: None,
+        }
+    }
+}
+
+fn call_tool(
+    ruby: &Ruby,
+    proc: Proc,
+    arguments: Option<JsonMap<String, JsonValue>>,
+    runtime: Value,
+    backtraces: bool,
+) -> Result<ToolOutput, Error> {
+    let args = match arguments {
+        Some(map) => json_value_to_ruby_value(ruby, &JsonValue::Object(map))?,
+        None => ruby.qnil().as_value(),
+    };
+    let output = match proc.call::<_, Value>((args, runtime)) {
+        Ok(output) => output,
+        Err(e) => return ToolException::from_error(e, backtraces).map(ToolOutput::Exception),
+    };
+    if let Some(blocks) = content::error_blocks(output) {
+        return Ok(ToolOutput::Error(blocks));
+    }
+    if let Some(blocks) = content::blocks(ruby, output)? {
+        return Ok(ToolOutput::Content(blocks));
+    }
+    if RHash::from_value(output).is_none() && RArray::from_value(output).is_none() {
+        return Ok(ToolOutput::Text(String::try_convert(output)?));
+    }
+    Ok(match ruby_value_to_json_value(ruby, output)? {
+        JsonValue::Object(map) => ToolOutput::Structured(map),
+        value => ToolOutput::Structured(JsonMap::from_iter([("result".to_string(), value)])),
+    })
+}
+
+/// Builds the result of a call to tool `name`. Structured output is checked
+/// against the tool's output schema, if it has one, and also sent as text for
+/// clients that do not read `structuredContent`. Errors are sent as error
+/// results whether or not the tool has an output schema.
+fn tool_result(
+    name: &str,
+    output: ToolOutput,
+    output_schema: Option<&Schema>,
+) -> Result<CallToolResult, CallToolError> {
+    let structured = match (output, output_schema) {
+        (ToolOutput::Error(content), _) => {
+            return Ok(CallToolResult {
+                content,
+                is_error: Some(true),
+                meta: None,
+                structured_content: None,
+            })
+        }
+        (ToolOutput::Exception(exception), _) => return Ok(exception.to_result()),
+        (ToolOutput::Text(text), None) => {
+            return Ok(CallToolResult::text_content(vec![TextContent::new(
+                text, None, None,
+            )]))
+        }
+        (ToolOutput::Content(content), None) => {
+            return Ok(CallToolResult {
+                content,
+                is_error: None,
+                meta: None,
+                structured_content: None,
+            })
+        }
+        (ToolOutput::Text(_) | ToolOutput::Content(_), Some(_)) => {
+            return Err(CallToolError::new(std::io::Error::other(format!(
+                "Tool {name} has an output schema but did not return structured content"
+            ))))
+        }
+        (ToolOutput::Structured(structured), _) => structured,
+    };
+    if let Some(output_schema) = output_schema {
+        let errors = schema::validate(output_schema, &JsonValue::Object(structured.clone()))
+            .map_err(|e| {
+                CallToolError::new(std::io::Error::other(format!(
+                    "Output schema of tool {name} cannot be applied: {e}"
+                )))
+            })?;
+        if !errors.is_empty() {
+            return Err(CallToolError::new(std::io::Error::other(format!(
+                "Output of tool {name} does not match its output schema: {}",
+                errors.join("; ")
+            ))));
+        }
+    }
+    let text = serde_json::to_string(&structured).map_err(CallToolError::new)?;
+    let mut result = CallToolResult::text_content(vec![TextContent::new(text, None, None)]);
+    result.structured_content = Some(structured);
+    Ok(result)
+}
+
+/// Calls a prompt proc and returns the messages it built as JSON.
+fn call_prompt(
+    ruby: &Ruby,
+    proc: Proc,
+    arguments: Option<JsonValue>,
+    runtime: Value,
+) -> Result<JsonValue, Error> {
+    let args = match arguments {
+        Some(json) => json_value_to_ruby_value(ruby, &json)?,
+        None => ruby.qnil().as_value(),
+    };
+    let messages = proc.call::<_, Value>((args, runtime))?;
+    ruby_value_to_json_value(ruby, messages)
+}
+
+/// Calls a resource proc and returns the contents it read: text, or blob
+/// for a binary (ASCII-8BIT) String. The proc is passed the variables of the
+/// URI for a resource template, and the URI itself otherwise.
+fn call_resource(
+    ruby: &Ruby,
+    proc: Proc,
+    uri: String,
+    mime_type: Option<String>,
+    variables: Option<JsonMap<String, JsonValue>>,
+    runtime: Value,
+) -> Result<ReadResourceResultContentsItem, Error> {
+    let arg = match variables {
+        Some(map) => json_value_to_ruby_value(ruby, &JsonValue::Object(map))?,
+        None => ruby.str_new(&uri).as_value(),
+    };
+    let contents: RString = proc.call((arg, runtime))?;
+    if contents.enc_get() == ruby.ascii8bit_encindex() {
+        // SAFETY: no Ruby code runs while the bytes are borrowed
+        let blob = BASE64.encode(unsafe { contents.as_slice() });
+        return Ok(BlobResourceContents {
+            blob,
+            meta: None,
+            mime_type,
+            uri,
+        }
+        .into());
+    }
+    Ok(TextResourceContents {
+        meta: None,
+        mime_type,
+        text: contents.to_string()?,
+        uri,
+    }
+    .into())
+}
+
+impl ServerState {
+    fn register_tool(
+        &self,
+        ruby: &Ruby,
+        name: String,
+        arg_schema: Option<Value>,
+        output_schema: Option<Value>,
+        details: Option<Value>,
+        handler: Proc,
+    ) -> Result<(), Error> {
+        let details: ToolDetails = match details {
+            Some(val) => serde_json::from_value(ruby_value_to_json_value(ruby, val)?)
+                .map_err(|e| Error::new(ruby.exception_arg_error(), format!("{name}: {e}")))?,
+            None => ToolDetails::default(),
+        };
+        let object_schema = |val: Value, label: &str| {
+            schema::object_schema(ruby_value_to_json_value(ruby, val)?, label)
+                .map(Arc::new)
+                .map_err(|e| Error::new(ruby.exception_arg_error(), format!("{name}: {e}")))
+        };
+        let input_schema = match arg_schema {
+            Some(val) => object_schema(val, "input")?,
+            None => {
+                Arc::new(schema::object_schema(JsonValue::Object(JsonMap::new()), "input").unwrap())
+            }
+        };
+        let output_schema = output_schema
+            .map(|val| object_schema(val, "output"))
+            .transpose()?;
+        let timeout = details
+            .timeout
+            .map(|secs| {
+                Duration::try_from_secs_f64(secs)
+                    .ok()
+                    .filter(|timeout| !timeout.is_zero())
+                    .ok_or_else(|| {
+                        Error::new(
+                            ruby.exception_arg_error(),
+                            format!("{name}: invalid timeout: {secs}"),
+                        )
+                    })
+            })
+            .transpose()?;
+
+        let tool = Tool {
+            annotations: details.annotations,
+            description: details.description,
+            input_schema: ToolInputSchema::new(Vec::new(), None),
+            meta: details.meta,
+            name: name.clone(),
+            output_schema: None,
+            title: details.title,
+        };
+
+        let handler_fn = RubyHandler(BoxValue::new(handler));
+
+        let mut map = self
+            .tools
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "tools mutex poisoned"))?;
+        let seq = self.registration_seq(&map, &name);
+        map.insert(
+            name,
+            ToolEntry {
+                tool,
+                input_schema,
+                output_schema,
+                timeout,
+                handler: handler_fn,
+                seq,
+            },
+        );
+        self.tools_changed.send_replace(());
+        Ok(())
+    }
+
+    fn register_prompt(
+        &self,
+        ruby: &Ruby,
+        name: String,
+        description: Option<String>,
+        arguments: Option<Value>,
+        handler: Proc,
+    ) -> Result<(), Error> {
+        let parsed_args = match arguments {
+            Some(val) => {
+                let json = ruby_value_to_json_value(ruby, val)?;
+                parse_prompt_arguments(json)
+            }
+            None => Vec::new(),
+        };
+
+        let prompt = Prompt {
+            arguments: parsed_args,
+            description,
+            meta: None,
+            name: name.clone(),
+            title: None,
+        };
+
+        let mut map = self
+            .prompts
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "prompts mutex poisoned"))?;
+        let entry = PromptEntry {
+            prompt,
+            handler: RubyHandler(BoxValue::new(handler)),
+            seq: self.registration_seq(&map, &name),
+        };
+        map.insert(name, entry);
+        self.prompts_changed.send_replace(());
+        Ok(())
+    }
+
+    fn register_resource(
+        &self,
+        ruby: &Ruby,
+        uri: String,
+        name: String,
+        description: Option<String>,
+        mime_type: Option<String>,
+        handler: Proc,
+    ) -> Result<(), Error> {
+        let resource = Resource {
+            annotations: None,
+            description,
+            meta: None,
+            mime_type,
+            name,
+            size: None,
+            title: None,
+            uri: uri.clone(),
+        };
+        let mut map = self
+            .resources
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "resources mutex poisoned"))?;
+        let entry = ResourceEntry {
+            resource,
+            handler: RubyHandler(BoxValue::new(handler)),
+            seq: self.registration_seq(&map, &uri),
+        };
+        map.insert(uri, entry);
+        self.resources_changed.send_replace(());
+        Ok(())
+    }
+
+    fn register_resource_template(
+        &self,
+        ruby: &Ruby,
+        uri_template: String,
+        name: String,
+        description: Option<String>,
+        mime_type: Option<String>,
+        handler: Proc,
+    ) -> Result<(), Error> {
+        let parsed = UriTemplate::parse(&uri_template)
+            .map_err(|e| Error::new(ruby.exception_arg_error(), e))?;
+        let template = ResourceTemplate {
+            annotations: None,
+            description,
+            meta: None,
+            mime_type,
+            name,
+            title: None,
+            uri_template: uri_template.clone(),
+        };
+        let mut map = self.resource_templates.lock().map_err(|_| {
+            Error::new(
+                ruby.exception_runtime_error(),
+                "resource templates mutex poisoned",
+            )
+        })?;
+        let entry = ResourceTemplateEntry {
+            template,
+            uri_template: parsed,
+            handler: RubyHandler(BoxValue::new(handler)),
+            seq: self.registration_seq(&map, &uri_template),
+        };
+        map.insert(uri_template, entry);
+        self.resources_changed.send_replace(());
+        Ok(())
+    }
+
+    /// Returns the number of an entry registered under `key` in `map`. An
+    /// entry that replaces another keeps its number, as it keeps its place.
+    fn registration_seq<T: Listed>(&self, map: &IndexMap<String, T>, key: &str) -> u64 {
+        map.get(key).map_or_else(
+            || self.registrations.fetch_add(1, Ordering::Relaxed),
+            Listed::seq,
+        )
+    }
+
+    /// Removes tool `name`, returning whether it was registered. Calls already
+    /// running finish normally.
+    fn unregister_tool(&self, ruby: &Ruby, name: &str) -> Result<bool, Error> {
+        let mut map = self
+            .tools
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "tools mutex poisoned"))?;
+        let removed = map.shift_remove(name).is_some();
+        if removed {
+            self.tools_changed.send_replace(());
+        }
+        Ok(removed)
+    }
+
+    fn unregister_prompt(&self, ruby: &Ruby, name: &str) -> Result<bool, Error> {
+        let mut map = self
+            .prompts
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "prompts mutex poisoned"))?;
+        let removed = map.shift_remove(name).is_some();
+        if removed {
+            self.prompts_changed.send_replace(());
+        }
+        Ok(removed)
+    }
+
+    fn clear_tools(&self, ruby: &Ruby) -> Result<(), Error> {
+        let mut map = self
+            .tools
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "tools mutex poisoned"))?;
+        if !map.is_empty() {
+            map.clear();
+            self.tools_changed.send_replace(());
+        }
+        Ok(())
+    }
+
+    fn clear_prompts(&self, ruby: &Ruby) -> Result<(), Error> {
+        let mut map = self
+            .prompts
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "prompts mutex poisoned"))?;
+        if !map.is_empty() {
+            map.clear();
+            self.prompts_changed.send_replace(());
+        }
+        Ok(())
+    }
+
+    fn tool_names(&self, ruby: &Ruby) -> Result<Vec<String>, Error> {
+        let map = self
+            .tools
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "tools mutex poisoned"))?;
+        Ok(map.keys().cloned().collect())
+    }
+
+    fn prompt_names(&self, ruby: &Ruby) -> Result<Vec<String>, Error> {
+        let map = self
+            .prompts
+            .lock()
+            .map_err(|_| Error::new(ruby.exception_runtime_error(), "prompts mutex poisoned"))?;
+        Ok(map.keys().cloned().collect())
+    }
+
+    /// Looks up the input and output schemas of tool `name`.
+    fn tool_schemas(&self, name: &str) -> Option<(Arc<Schema>, Option<Arc<Schema>>)> {
+        let map = self.tools.lock().unwrap();
+        map.get(name)
+            .map(|entry| (entry.input_schema.clone(), entry.output_schema.clone()))
+    }
+
+    /// Returns how long a call to tool `name` may run, falling back to
+    /// `default` when the tool does not set its own timeout.
+    fn tool_timeout(&self, name: &str, default: Option<Duration>) -> Option<Duration> {
+        let map = self.tools.lock().unwrap();
+        map.get(name).and_then(|entry| entry.timeout).or(default)
+    }
+
+    /// Looks up the proc of tool `name`. Must be called with the GVL held, so
+    /// the proc cannot be collected before it is called.
+    fn tool_proc(&self, name: &str) -> Option<Proc> {
+        let map = self.tools.lock().unwrap();
+        map.get(name).map(|entry| *entry.handler.0.as_ref())
+    }
+
+    /// Looks up the proc and description of prompt `name`. Must be called with
+    /// the GVL held.
+    fn prompt_proc(&self, name: &str) -> Option<(Proc, Option<String>)> {
+        let map = self.prompts.lock().unwrap();
+        map.get(name)
+            .map(|entry| (*entry.handler.0.as_ref(), entry.prompt.description.clone()))
+    }
+
+    /// Whether `uri` is served by a resource or a resource template.
+    fn has_resource(&self, uri: &str) -> bool {
+        self.resources.lock().unwrap().contains_key(uri)
+            || self
+                .resource_templates
+                .lock()
+                .unwrap()
+                .values()
+                .any(|entry| entry.uri_template.match_uri(uri).is_some())
+    }
+
+    /// Tells the sessions subscribed to `uri` that the resource changed.
+    fn notify_resource_updated(&self, uri: String) {
+        // fails only when no session is listening
+        let _ = self.resource_updates.0.send(uri);
+    }
+
+    /// Looks up the proc and MIME type of the resource at `uri`, along with
+    /// the variables of the URI when it is served by a resource template.
+    /// Resources take precedence over templates, which are tried in the order
+    /// they were registered. Must be called with the GVL held.
+    fn resource_proc(&self, uri: &str) -> Option<ResourceProc> {
+        if let Some(entry) = self.resources.lock().unwrap().get(uri) {
+            let mime_type = entry.resource.mime_type.clone();
+            return Some((*entry.handler.0.as_ref(), mime_type, None));
+        }
+        let templates = self.resource_templates.lock().unwrap();
+        templates.values().find_map(|entry| {
+            let variables = entry.uri_template.match_uri(uri)?;
+            let mime_type = entry.template.mime_type.clone();
+            Some((*entry.handler.0.as_ref(), mime_type, Some(variables)))
+        })
+    }
+
+    /// Queues `call` on the dispatcher, registered as an in-flight call named
+    /// `name` (see [`InFlightCall`]) until it has run, and passes it the runtime object once it
+    /// starts. The object is invalidated when the returned guard is dropped.
+    /// The outcome is sent on the returned channel, which is closed without a
+    /// value if the call is aborted. Returns `None` when the server is not
+    /// running.
+    fn dispatch_call<T, F>(
+        self: &Arc<Self>,
+        name: &str,
+        wrapper: &RubyMcpServer,
+        call: F,
+    ) -> Option<(CallGuard, oneshot::Receiver<Result<T, String>>)>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Ruby, Value) -> Result<T, Error> + Send + 'static,
+    {
+        let id = self.register_call(name);
+        let runtime = wrapper.clone();
+        let (result_tx, result_rx) = oneshot::channel();
+        let state = self.clone();
+
+        let job: Job = Box::new(move |ruby| {
+            if !state.start_call(id, ruby.thread_current()) {
+                // aborted while it was queued
+                return Ok(());
+            }
+            let result = call(ruby, runtime.into_value_with(ruby));
+            state.finish_call(id);
+            match result {
+                Ok(value) => {
+                    let _ = result_tx.send(Ok(value));
+                    Ok(())
+                }
+                // let Thread#kill finish unwinding the thread
+                Err(e) if matches!(e.error_type(), ErrorType::Jump(_)) => Err(e),
+                Err(e) => {
+                    let _ = result_tx.send(Err(e.to_string()));
+                    Ok(())
+                }
+            }
+        });
+        if self.dispatcher.submit(job).is_err() {
+            self.finish_call(id);
+            return None;
+        }
+        Some((
+            CallGuard {
+                runtime: wrapper.clone(),
+                id,
+            },
+            result_rx,
+        ))
+    }
+
+    fn register_call(&self, name: &str) -> u64 {
+        let mut calls = self.calls.lock().unwrap();
+        let id = calls.next_id;
+        calls.next_id += 1;
+        calls.calls.insert(
+            id,
+            InFlightCall {
+                name: name.to_string(),
+                thread: None,
+            },
+        );
+        id
+    }
+
+    /// Records the thread running call `id`. Returns false if the call was
+    /// aborted before it started.
+    fn start_call(&self, id: u64, thread: Thread) -> bool {
+        match self.calls.lock().unwrap().calls.get_mut(&id) {
+            Some(call) => {
+                call.thread = Some(Opaque::from(thread));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn finish_call(&self, id: u64) {
+        self.calls.lock().unwrap().calls.remove(&id);
+        self.calls_changed.notify_waiters();
+    }
+
+    async fn calls_finished(&self) {
+        loop {
+            let changed = self.calls_changed.notified();
+            if self.calls.lock().unwrap().calls.is_empty() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Kills the thread of call `id`, or drops the call if it has not started
+    /// yet. Must be called with the GVL held.
+    fn abort_call(&self, ruby: &Ruby, id: u64) {
+        let mut calls = self.calls.lock().unwrap();
+        match calls.calls.get(&id).map(|call| call.thread) {
+            Some(Some(thread)) => {
+                let _ = ruby.get_inner(thread).kill();
+            }
+            Some(None) => {
+                calls.calls.remove(&id);
+                self.calls_changed.notify_waiters();
+            }
+            None => {}
+        }
+    }
+
+    /// Waits for the outcome of `call`. The call is aborted when it runs
+    /// longer than `timeout`, or is still running `grace` after the client
+    /// cancelled it. Must be called on the thread that drives the sessions.
+    async fn wait_for_call<T>(
+        &self,
+        call: &CallGuard,
+        mut outcome: oneshot::Receiver<Result<T, String>>,
+        timeout: Option<Duration>,
+        mut cancellation: Cancellation,
+        grace: Duration,
+    ) -> CallEnd<T> {
+        let deadline = async {
+            match timeout {
+                Some(timeout) => {
+                    tokio::time::sleep(timeout).await;
+                    timeout
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let cancelled = async {
+            cancellation.cancelled().await;
+            tokio::time::sleep(grace).await;
+        };
+        let interrupted = tokio::select! {
+            outcome = &mut outcome => return outcome.map_or(CallEnd::Aborted, CallEnd::Finished),
+            timeout = deadline => CallEnd::TimedOut(timeout),
+            _ = cancelled => CallEnd::Cancelled,
+        };
+        with_gvl(|| self.abort_call(&Ruby::get().unwrap(), call.id));
+        // answered once the killed call has unwound, since it may have
+        // finished meanwhile, unless it is stuck where Ruby cannot interrupt
+        // it
+        match tokio::time::timeout(KILL_GRACE, &mut outcome).await {
+            Ok(Ok(outcome)) => CallEnd::Finished(outcome),
+            Ok(Err(_)) => interrupted,
+            Err(_) => {
+                // forget the call, so its result is dropped whenever it comes
+                self.finish_call(call.id);
+                interrupted
+            }
+        }
+    }
+
+    /// Kills the threads of the running calls, drops the queued ones, and
+    /// returns the names of both. Must be called with the GVL held.
+    fn abort_calls(&self, ruby: &Ruby) -> Vec<String> {
+        let mut aborted = Vec::new();
+        self.calls.lock().unwrap().calls.retain(|_, call| {
+            aborted.push(call.name.clone());
+            match call.thread {
+                Some(thread) => {
+                    let _ = ruby.get_inner(thread).kill();
+                    true
+                }
+                None => false,
+            }
+        });
+        self.calls_changed.notify_waiters();
+        aborted.sort();
+        aborted
+    }
+
+    /// Stops taking new tool calls and gives the ones still running `grace`
+    /// to finish while `server` keeps delivering their responses. Calls that
+    /// are still running after that are aborted and their names returned.
+    async fn drain<F: Future>(&self, mut server: Pin<&mut F>, grace: Duration) -> Vec<String> {
+        self.draining.store(true, Ordering::Release);
+        tokio::select! {
+            _ = self.calls_finished() => return Vec::new(),
+            _ = &mut server => return Vec::new(),
+            _ = tokio::time::sleep(grace) => {}
+        }
+
+        let aborted = with_gvl(|| self.abort_calls(&Ruby::get().unwrap()));
+        // wait for them to unwind, so none still runs once the server stops
+        tokio::select! {
+            _ = self.calls_finished() => {}
+            _ = &mut server => {}
+        }
+        aborted
+    }
+
+    fn set_reload_handler(&self, handler: Option<Proc>) {
+        *self.reload_handler.lock().unwrap() = handler.map(|proc| RubyHandler(BoxValue::new(proc)));
+    }
+
+    /// Marks the server as running and returns the number of the new run, or
+    /// `None` when it is already running. A shutdown requested from then on
+    /// is kept until the run picks it up, even while it is still starting.
+    fn begin_run(&self) -> Option<u64> {
+        let mut run = None;
+        // under the lock `request_shutdown` takes, so a request checks the
+        // run number and the running flag together
+        self.shutdown.send_if_modified(|_| {
+            if !self.running.swap(true, Ordering::AcqRel) {
+                run = Some(self.runs.fetch_add(1, Ordering::AcqRel) + 1);
+            }
+            false
+        });
+        run
+    }
+
+    fn is_current_run(&self, run: u64) -> bool {
+        self.running.load(Ordering::Acquire) && self.runs.load(Ordering::Acquire) == run
+    }
+
+    /// Marks the server as stopped. Cleared under the lock `request_shutdown`
+    /// takes, so a request made as the server stops is either seen by this run
+    /// or dropped, never left behind for the next one.
+    fn end_run(&self) {
+        self.shutdown.send_modify(|request| {
+            *request = None;
+            self.draining.store(false, Ordering::Release);
+            self.running.store(false, Ordering::Release);
+        });
+    }
+
+    /// Asks the server to shut down, when `run` is given only if that run is
+    /// the current one. Does nothing when it is not running, so that a late
+    /// request cannot stop a later run.
+    fn request_shutdown(&self, grace: Option<Duration>, run: Option<u64>) {
+        self.shutdown.send_if_modified(|request| {
+            let current = match run {
+                Some(run) => self.is_current_run(run),
+                None => self.running.load(Ordering::Acquire),
+            };
+            if current {
+                *request = Some(ShutdownRequest { grace });
+            }
+            current
+        });
+    }
+}
+
+pub fn register_tool(
+    ruby: &Ruby,
+    name: String,
+    arg_schema: Option<Value>,
+    output_schema: Option<Value>,
+    details: Option<Value>,
+    handler: Proc,
+) -> Result<(), Error> {
+    default_server().register_tool(ruby, name, arg_schema, output_schema, details, handler)
+}
+
+pub fn register_prompt(
+    ruby: &Ruby,
+    name: String,
+    description: Option<String>,
+    arguments: Option<Value>,
+    handler: Proc,
+) -> Result<(), Error> {
+    default_server().register_prompt(ruby, name, description, arguments, handler)
+}
+
+pub fn register_resource(
+    ruby: &Ruby,
+    uri: String,
+    name: String,
+    description: Option<String>,
+    mime_type: Option<String>,
+    handler: Proc,
+) -> Result<(), Error> {
+    default_server().register_resource(ruby, uri, name, description, mime_type, handler)
+}
+
+pub fn register_resource_template(
+    ruby: &Ruby,
+    uri_template: String,
+    name: String,
+    description: Option<String>,
+    mime_type: Option<String>,
+    handler: Proc,
+) -> Result<(), Error> {
+    default_server().register_resource_template(
+        ruby,
+        uri_template,
+        name,
+        description,
+        mime_type,
+        handler,
+    )
+}
+
+pub fn notify_resource_updated(uri: String) {
+    default_server().notify_resource_updated(uri);
+}
+
+pub struct MyServerHandler {
+    state: Arc<ServerState>,
+    /// The session the handler answers, handed to the procs it calls.
+    peer: Arc<Peer>,
+    /// URIs of the resources the session subscribed to.
+    subscriptions: Arc<Mutex<HashSet<String>>>,
+    error_backtraces: bool,
+    page_size: usize,
+    /// Timeout of tools that do not set their own.
+    tool_timeout: Option<Duration>,
+    /// Time a cancelled tool call gets to stop by itself before it is aborted.
+    cancel_grace: Duration,
+    logger: Logger,
+}
+
+/// Returns the page of `map` following `cursor` and the cursor of the page
+/// after it. A cursor is the base64 encoded number of the last entry of the
+/// page before, see [`Listed`], so pages stay consistent when entries are
+/// added or removed meanwhile.
+fn page<'a, T: Listed>(
+    map: &'a IndexMap<String, T>,
+    cursor: Option<&str>,
+    size: usize,
+) -> Result<(&'a Slice<String, T>, Option<String>), RpcError> {
+    let start = match cursor {
+        Some(cursor) => {
+            let seq = BASE64
+                .decode(cursor)
+                .ok()
+                .and_then(|seq| String::from_utf8(seq).ok()?.parse::<u64>().ok())
+                .ok_or_else(|| {
+                    RpcError::invalid_params().with_message("Invalid cursor".to_string())
+                })?;
+            map.values()
+                .position(|entry| entry.seq() > seq)
+                .unwrap_or(map.len())
+        }
+        None => 0,
+    };
+    let end = map.len().min(start + size);
+    let next_cursor = (end < map.len())
+        .then(|| map.get_index(end - 1))
+        .flatten()
+        .map(|(_, entry)| BASE64.encode(entry.seq().to_string()));
+    Ok((&map.as_slice()[start..end], next_cursor))
+}
+
+#[async_trait]
+impl ServerHandler for MyServerHandler {
+    async fn handle_list_prompts_request(
+        &self,
+        request: ListPromptsRequest,
+        _runtime: &dyn McpServer,
+    ) -> Result<ListPromptsResult, RpcError> {
+        let cursor = request.params.and_then(|params| params.cursor);
+        let (prompts, next_cursor) = {
+            let map = self.state.prompts.lock().map_err(|_| {
+                RpcError::internal_error().with_message("prompts mutex poisoned".to_string())
+            })?;
+            let (entries, next_cursor) = page(&map, cursor.as_deref(), self.page_size)?;
+            (
+                entries.values().map(|p| p.prompt.clone()).collect(),
+                next_cursor,
+            )
+        };
+        Ok(ListPromptsResult {
+            prompts,
+            meta: None,
+            next_cursor,
+        })
+    }
+
+    async fn handle_get_prompt_request(
+        &self,
+        request: GetPromptRequest,
+        _runtime: &dyn McpServer,
+    ) -> Result<GetPromptResult, RpcError> {
+        if self.state.draining.load(Ordering::Acquire) {
+            return Err(
+                RpcError::internal_error().with_message("server is shutting down".to_string())
+            );
+        }
+        let name = request.params.name;
+        let arguments = request.params.arguments.map(|map| {
+            JsonValue::Object(
+                map.into_iter()
+                    .map(|(k, v)| (k, JsonValue::String(v)))
+                    .collect(),
+            )
+        });
+        let wrapper = RubyMcpServer::new(self.peer.clone(), None, Cancellation::default());
+        let state = self.state.clone();
+        let prompt = name.clone();
+        let label = format!("prompt:{name}");
+        let (call, outcome) = self
+            .state
+            .dispatch_call(&label, &wrapper, move |ruby, runtime| {
+                match state.prompt_proc(&prompt) {
+                    Some((proc, description)) => call_prompt(ruby, proc, arguments, runtime)
+                        .map(|messages| Some((messages, description))),
+                    None => Ok(None),
+                }
+            })
+            .ok_or_else(|| {
+                RpcError::internal_error().with_message("server is shutting down".to_string())
+            })?;
+        let outcome = outcome.await;
+        drop(call);
+
+        let (json, description) = match outcome {
+            Ok(Ok(Some(prompt))) => prompt,
+            Ok(Ok(None)) => {
+                return Err(RpcError::invalid_params().with_message("Unknown prompt".to_string()))
+            }
+            Ok(Err(message)) => return Err(RpcError::internal_error().with_message(message)),
+            Err(_) => {
+                return Err(
+                    RpcError::internal_error().with_message("prompt call was aborted".to_string())
+                )
+            }
+        };
+        let messages: Vec<PromptMessage> = serde_json::from_value(json)
+            .map_err(|e| RpcError::internal_error().with_message(e.to_string()))?;
+
+        Ok(GetPromptResult {
+            description,
+            messages,
+            meta: None,
+        })
+    }
+
+    async fn handle_list_resources_request(
+        &self,
+        request: ListResourcesRequest,
+        _runtime: &dyn McpServer,
+    ) -> Result<ListResourcesResult, RpcError> {
+        let cursor = request.params.and_then(|params| params.cursor);
+        let map = self.state.resources.lock().map_err(|_| {
+            RpcError::internal_error().with_message("resources mutex poisoned".to_string())
+        })?;
+        let (entries, next_cursor) = page(&map, cursor.as_deref(), self.page_size)?;
+        Ok(ListResourcesResult {
+            resources: entries.values().map(|r| r.resource.clone()).collect(),
+            meta: None,
+            next_cursor,
+        })
+    }
+
+    async fn handle_list_resource_templates_request(
+        &self,
+        request: ListResourceTemplatesRequest,
+        _runtime: &dyn McpServer,
+    ) -> Result<ListResourceTemplatesResult, RpcError> {
+        let cursor = request.params.and_then(|params| params.cursor);
+        let map = self.state.resource_templates.lock().map_err(|_| {
+            RpcError::internal_error().with_message("resource templates mutex poisoned".to_string())
+        })?;
+        let (entries, next_cursor) = page(&map, cursor.as_deref(), self.page_size)?;
+        Ok(ListResourceTemplatesResult {
+            resource_templates: entries.values().map(|t| t.template.clone()).collect(),
+            meta: None,
+            next_cursor,
+        })
+    }
+
+    async fn handle_read_resource_request(
+        &self,
+        request: ReadResourceRequest,
+        _runtime: &dyn McpServer,
+    ) -> Result<ReadResourceResult, RpcError> {
+        if self.state.draining.load(Ordering::Acquire) {
+            return Err(
+                RpcError::internal_error().with_message("server is shutting down".to_string())
+            );
+        }
+        let uri = request.params.uri;
+        let wrapper = RubyMcpServer::new(self.peer.clone(), None, Cancellation::default());
+        let state = self.state.clone();
+        let resource = uri.clone();
+        let label = format!("resource:{uri}");
+        let (call, outcome) = self
+            .state
+            .dispatch_call(&label, &wrapper, move |ruby, runtime| {
+                match state.resource_proc(&resource) {
+                    Some((proc, mime_type, variables)) => {
+                        call_resource(ruby, proc, resource, mime_type, variables, runtime).map(Some)
+                    }
+                    None => Ok(None),
+                }
+            })
+            .ok_or_else(|| {
+                RpcError::internal_error().with_message("server is shutting down".to_string())
+            })?;
+        let outcome = outcome.await;
+        drop(call);
+
+        match outcome {
+            Ok(Ok(Some(contents))) => Ok(ReadResourceResult {
+                contents: vec![contents],
+                meta: None,
+            }),
+            Ok(Ok(None)) => Err(resource_not_found(uri)),
+            Ok(Err(message)) => Err(RpcError::internal_error().with_message(message)),
+            Err(_) => {
+                Err(RpcError::internal_error()
+                    .with_message("resource read was aborted".to_string()))
+            }
+        }
+    }
+
+    async fn handle_subscribe_request(
+        &self,
+        request: SubscribeRequest,
+        _runtime: &dyn McpServer,
+    ) -> Result<McpResult, RpcError> {
+        let uri = request.params.uri;
+        if !self.state.has_resource(&uri) {
+            return Err(resource_not_found(uri));
+        }
+        self.subscriptions.lock().unwrap().insert(uri);
+        Ok(McpResult::default())
+    }
+
+    async fn handle_unsubscribe_request(
+        &self,
+        request: UnsubscribeRequest,
+        _runtime: &dyn McpServer,
+    ) -> Result<McpResult, RpcError> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .remove(&request.params.uri);
+        Ok(McpResult::default())
+    }
+}
+
+#[async_trait]
+impl SessionHandler for MyServerHandler {
+    async fn handle_list_tools(
+        &self,
+        request: ListToolsRequest,
+        _runtime: &dyn McpServer,
+    ) -> Result<JsonMap<String, JsonValue>, RpcError> {
+        let cursor = request.params.and_then(|params| params.cursor);
+        let map = self.state.tools.lock().map_err(|_| {
+            RpcError::internal_error().with_message("tools mutex poisoned".to_string())
+        })?;
+        let (entries, next_cursor) = page(&map, cursor.as_deref(), self.page_size)?;
+        let tools = entries
+            .values()
+            .map(ToolEntry::to_json)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| RpcError::internal_error().with_message(e.to_string()))?;
+        let mut result = JsonMap::from_iter([("tools".to_string(), tools.into())]);
+        if let Some(next_cursor) = next_cursor {
+            result.insert("nextCursor".to_string(), next_cursor.into());
+        }
+        Ok(result)
+    }
+
+    async fn handle_call_tool(
+        &self,
+        request: CallToolRequest,
+        progress_token: Option<ProgressToken>,
+        cancellation: Cancellation,
+        _runtime: &dyn McpServer,
+    ) -> Result<CallToolResult, CallToolError> {
+        if self.state.draining.load(Ordering::Acquire) {
+            return Err(CallToolError::new(std::io::Error::other(
+                "server is shutting down",
+            )));
+        }
+        let name = request.params.name;
+        let arguments = request.params.arguments;
+        let mut output_schema = None;
+        if let Some((input_schema, tool_output_schema)) = self.state.tool_schemas(&name) {
+            let instance = JsonValue::Object(arguments.clone().unwrap_or_default());
+            let errors = schema::validate(&input_schema, &instance).map_err(|e| {
+                CallToolError::new(std::io::Error::other(format!(
+                    "Input schema of tool {name} cannot be applied: {e}"
+                )))
+            })?;
+            if !errors.is_empty() {
+                let message = format!("Invalid arguments for tool {name}: {}", errors.join("; "));
+                return Err(CallToolError::new(
+                    RpcError::invalid_params()
+                        .with_message(message)
+                        .with_data(Some(serde_json::json!({ "errors": errors }))),
+                ));
+            }
+            output_schema = tool_output_schema;
+        }
+        let timeout = self.state.tool_timeout(&name, self.tool_timeout);
+        let wrapper = RubyMcpServer::new(self.peer.clone(), progress_token, cancellation.clone());
+        let state = self.state.clone();
+        let tool = name.clone();
+        let backtraces = self.error_backtraces;
+        let (call, outcome) = self
+            .state
+            .dispatch_call(&name, &wrapper, move |ruby, runtime| {
+                match state.tool_proc(&tool) {
+                    Some(proc) => call_tool(ruby, proc, arguments, runtime, backtraces).map(Some),
+                    None => Ok(None),
+                }
+            })
+            .ok_or_else(|| CallToolError::new(std::io::Error::other("server is shutting down")))?;
+        let end = self
+            .state
+            .wait_for_call(&call, outcome, timeout, cancellation, self.cancel_grace)
+            .await;
+        drop(call);
+        match end {
+            CallEnd::Finished(Ok(Some(output))) => {
+                tool_result(&name, output, output_schema.as_deref())
+            }
+            CallEnd::Finished(Ok(None)) => Err(CallToolError::unknown_tool(name)),
+            CallEnd::Finished(Err(message)) => {
+                Err(CallToolError::new(std::io::Error::other(message)))
+            }
+            CallEnd::Aborted => Err(CallToolError::new(std::io::Error::other(
+                "tool call was aborted",
+            ))),
+            CallEnd::TimedOut(timeout) => {
+                let message = format!("Tool {name} timed out after {timeout:?}");
+                self.logger.warn(message.clone());
+                Err(CallToolError::new(std::io::Error::other(message)))
+            }
+            // not sent, the client no longer expects a response
+            CallEnd::Cancelled => Err(CallToolError::new(std::io::Error::other(
+                "tool call was cancelled",
+            ))),
+        }
+    }
+
+    fn notifications(&self) -> BoxStream<'static, NotificationFromServer> {
+        let lists = futures::stream::select(
+            list_changes(self.state.tools_changed.subscribe(), || {
+                ToolListChangedNotification::new(None).into()
+            }),
+            list_changes(self.state.prompts_changed.subscribe(), || {
+                PromptListChangedNotification::new(None).into()
+            }),
+        );
+        let lists = futures::stream::select(
+            lists,
+            list_changes(self.state.resources_changed.subscribe(), || {
+                ResourceListChangedNotification::new(None).into()
+            }),
+        );
+        futures::stream::select(
+            lists,
+            resource_updates(
+                self.state.resource_updates.0.subscribe(),
+                self.subscriptions.clone(),
+            ),
+        )
+        .boxed()
     }
+}
 
-    pub fn client_supports_sampling(&self) -> Result<Option<bool>, Error> {
-        Ok(self.runtime()?.client_supports_sampling())
-    }
+/// Yields a `notifications/resources/updated` for each update of a resource
+/// in `subscriptions`.
+fn resource_updates(
+    updates: broadcast::Receiver<String>,
+    subscriptions: Arc<Mutex<HashSet<String>>>,
+) -> impl Stream<Item = NotificationFromServer> {
+    futures::stream::unfold(updates, move |mut updates| {
+        let subscriptions = subscriptions.clone();
+        async move {
+            loop {
+                match updates.recv().await {
+                    Ok(uri) if subscriptions.lock().unwrap().contains(&uri) => {
+                        let params = ResourceUpdatedNotificationParams { uri };
+                        let notification = ResourceUpdatedNotification::new(params).into();
+                        return Some((notification, updates));
+                    }
+                    // updates missed by a session that fell behind are dropped
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
 
-    pub fn create_message(&self, params: Value) -> Result<Value, Error> {
-        let ruby = Ruby::get().unwrap();
-        let runtime = self.runtime()?;
-        let json_value = ruby_value_to_json_value(&ruby, params)?;
-        let request_params: rust_mcp_sdk::schema::CreateMessageRequestParams =
-            serde_json::from_value(json_value)
-                .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))?;
+/// Time a registry change waits for further changes before clients are
+/// notified, so registering many tools at once sends one notification.
+const LIST_CHANGED_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Yields `notification()` after each burst of changes.
+fn list_changes(
+    changes: watch::Receiver<()>,
+    notification: fn() -> NotificationFromServer,
+) -> impl Stream<Item = NotificationFromServer> {
+    futures::stream::unfold(changes, move |mut changes| async move {
+        changes.changed().await.ok()?;
+        tokio::time::sleep(LIST_CHANGED_DEBOUNCE).await;
+        changes.borrow_and_update();
+        Some((notification(), changes))
+    })
+}
 
-        let runtime_handle = RUNTIME.get().expect("Tokio not initialised");
-        let handle = runtime_handle.handle();
+fn server_details(config: &ServerConfig) -> InitializeResult {
+    InitializeResult {
+        server_info: Implementation {
+            name: config.name.clone(),
+            title: config.title.clone(),
+            version: config.version.clone(),
+        },
+        capabilities: ServerCapabilities {
+            tools: Some(ServerCapabilitiesTools {
+                list_changed: Some(true),
+            }),
+            prompts: Some(ServerCapabilitiesPrompts {
+                list_changed: Some(true),
+            }),
+            resources: Some(ServerCapabilitiesResources {
+                list_changed: Some(true),
+                subscribe: Some(true),
+            }),
+            ..Default::default()
+        },
+        meta: None,
+        instructions: config.instructions.clone(),
+        protocol_version: LATEST_PROTOCOL_VERSION.to_string(),
+    }
+}
 
-        let result = if tokio::runtime::Handle::try_current().is_ok() {
-            tokio::task::block_in_place(|| {
-                handle.block_on(async { runtime.create_message(request_params).await })
-            })
-        } else {
-            handle.block_on(async { runtime.create_message(request_params).await })
+async fn serve(
+    config: ServerConfig,
+    state: Arc<ServerState>,
+    logger: Logger,
+) -> Result<(), ServeError> {
+    let error_backtraces = config.error_backtraces;
+    let page_size = config.page_size;
+    let tool_timeout = config.tool_timeout.map(Duration::from_secs_f64);
+    let cancel_grace = Duration::from_secs_f64(config.cancel_grace);
+    let new_handler = move |peer| MyServerHandler {
+        state: state.clone(),
+        peer,
+        subscriptions: Arc::default(),
+        error_backtraces,
+        page_size,
+        tool_timeout,
+        cancel_grace,
+        logger: logger.clone(),
+    };
+    match config.transport {
+        TransportKind::Stdio => {
+            let server = SessionRuntime::new(
+                server_details(&config),
+                logger,
+                new_handler,
+                tokio::io::stdin(),
+                tokio::io::stdout(),
+            );
+            server.start().await.map_err(ServeError::from)
+        }
+        TransportKind::Http => crate::http::serve(
+            &config.http_addr(),
+            config.http_options(),
+            server_details(&config),
+            logger,
+            new_handler,
+        )
+        .await
+        .map_err(ServeError::from),
+        TransportKind::Unix => {
+            let path = config
+                .socket_path
+                .as_deref()
+                .ok_or_else(|| ServeError::Transport("missing socket_path".to_string()))?;
+            crate::unix_socket::serve(path, server_details(&config), logger, new_handler)
+                .await
+                .map_err(ServeError::from)
         }
-        .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))?;
-
-        let json_result = serde_json::to_value(result)
-            .map_err(|e| Error::new(ruby.exception_runtime_error(), e.to_string()))?;
-        json_value_to_ruby_value(&ruby, &json_result)
     }
 }
 
-pub fn register_tool(
-    ruby: &Ruby,
-    name: String,
-    description: Option<String>,
-    arg_schema: Option<Value>,
-    handler: Proc,
-) -> Result<(), Error> {
-    let schema = match arg_schema {
-        Some(val) => {
-            let json = ruby_value_to_json_value(ruby, val)?;
-            parse_tool_input_schema(json)
+/// Serves until the transport ends or shutdown is requested, then drains the
+/// in-flight tool calls. `reload_handler` is called on SIGHUP.
+async fn run(
+    state: Arc<ServerState>,
+    config: ServerConfig,
+    reload_handler: Option<Opaque<Proc>>,
+    logger: Logger,
+) -> Result<Stopped, ServeError> {
+    let default_grace = Duration::from_secs_f64(config.shutdown_grace);
+    let mut shutdown = state.shutdown.subscribe();
+    // installed before serving so no signal is missed once clients connect
+    let mut signals = Signals::new(config.shutdown_signals())?;
+    let hangups = match reload_handler {
+        Some(_) => Signals::new(&["SIGHUP"])?,
+        None => Signals::new::<&str>(&[])?,
+    };
+    let server = serve(config, state.clone(), logger.clone());
+    tokio::pin!(server);
+
+    let (reason, grace) = tokio::select! {
+        result = &mut server => {
+            return result.map(|()| Stopped {
+                reason: StopReason::ClientDisconnected,
+                aborted_calls: Vec::new(),
+            });
         }
-        None => ToolInputSchema::new(Vec::new(), None),
+        request = shutdown.wait_for(Option::is_some) => {
+            let grace = request.ok().and_then(|request| request.and_then(|r| r.grace));
+            (StopReason::ShutdownRequested, grace)
+        }
+        signal = signals.recv() => (StopReason::Signal(signal), None),
+        never = reload_on_hangup(hangups, reload_handler, &state.dispatcher, &logger) => match never {},
     };
+    let aborted_calls = state.drain(server, grace.unwrap_or(default_grace)).await;
+    Ok(Stopped {
+        reason,
+        aborted_calls,
+    })
+}
 
-    let tool = Tool {
-        annotations: None,
-        description,
-        input_schema: schema,
-        meta: None,
-        name: name.clone(),
-        output_schema: None,
-        title: None,
+/// Reads and checks the options `start_server` was given, and returns them
+/// with the reload handler to install.
+fn configure(
+    state: &Arc<ServerState>,
+    ruby: &Ruby,
+    options: Option<Value>,
+) -> Result<(ServerConfig, Option<RubyHandler>), Error> {
+    let mut config: ServerConfig = match options {
+        Some(val) => serde_json::from_value(ruby_value_to_json_value(ruby, val)?)
+            .map_err(|e| Error::new(ruby.exception_arg_error(), e.to_string()))?,
+        None => ServerConfig::default(),
     };
+    config
+        .validate()
+        .map_err(|e| Error::new(ruby.exception_arg_error(), e))?;
+    config
+        .load_instructions()
+        .map_err(|e| Error::new(ruby.exception_io_error(), e.to_string()))?;
+
+    // Held until the server stops, so the proc stays alive even if another
+    // one is registered meanwhile.
+    let reload_handler = if config.handle_signals {
+        state.reload_handler.lock().unwrap().clone()
+    } else {
+        None
+    };
+    if reload_handler.is_some()
+        && config
+            .shutdown_signals
+            .iter()
+            .any(|name| signals::canonical_name(name) == Some("SIGHUP"))
+    {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "SIGHUP cannot both shut the server down and reload it",
+        ));
+    }
+    Ok((config, reload_handler))
+}
 
-    let handler_fn = RubyHandler(BoxValue::new(handler));
-
-    let mut map = tools()
-        .lock()
-        .map_err(|_| Error::new(ruby.exception_runtime_error(), "tools mutex poisoned"))?;
-    map.insert(
-        name,
-        ToolEntry {
-            tool,
-            handler: handler_fn,
-        },
-    );
-    Ok(())
+fn already_running(ruby: &Ruby) -> Error {
+    Error::new(ruby.exception_runtime_error(), "server is already running")
 }
 
-pub fn register_prompt(
-    ruby: &Ruby,
-    name: String,
-    description: Option<String>,
-    arguments: Option<Value>,
-    handler: Proc,
-) -> Result<(), Error> {
-    let parsed_args = match arguments {
-        Some(val) => {
-            let json = ruby_value_to_json_value(ruby, val)?;
-            parse_prompt_arguments(json)
+/// Serves until the server stops. Takes the options, the log proc and the
+/// number of a run begun with `begin_run`; without a run number one is begun
+/// here.
+fn start(state: &Arc<ServerState>, ruby: &Ruby, args: &[Value]) -> Result<RHash, Error> {
+    let args = scan_args::<(), (Option<Value>, Option<Proc>, Option<u64>), (), (), (), ()>(args)?;
+    let (options, log, run_id) = args.optional;
+    match run_id {
+        Some(id) if !state.is_current_run(id) => {
+            return Err(Error::new(
+                ruby.exception_runtime_error(),
+                format!("run {id} has already ended"),
+            ));
+        }
+        Some(_) => {}
+        None => {
+            state.begin_run().ok_or_else(|| already_running(ruby))?;
+        }
+    }
+    let (config, reload_handler) = match configure(state, ruby, options) {
+        Ok(configured) => configured,
+        Err(e) => {
+            state.end_run();
+            return Err(e);
         }
-        None => Vec::new(),
     };
 
-    let prompt = Prompt {
-        arguments: parsed_args,
-        description,
-        meta: None,
-        name: name.clone(),
-        title: None,
-    };
+    let runtime = RUNTIME.get_or_init(|| Runtime::new().expect("Failed to create Tokio runtime"));
+    let log_handler = log.map(|proc| RubyHandler(BoxValue::new(proc)));
+    let reload_proc = reload_handler
+        .as_ref()
+        .map(|handler| Opaque::from(*handler.0.as_ref()));
+    let logger = log_handler
+        .as_ref()
+        .map(|handler| ruby_logger(state, Opaque::from(*handler.0.as_ref())))
+        .unwrap_or_default();
+    state
+        .dispatcher
+        .start(ruby, config.worker_threads, runtime.handle().clone());
+    let result =
+        nogvl(|| runtime.block_on(run(state.clone(), config.clone(), reload_proc, logger)));
+    state.dispatcher.stop();
+    state.end_run();
+
+    let stopped = result.map_err(|e| e.into_ruby_error(ruby))?;
+    // the pool has stopped, so the proc is called directly
+    if let (Some(handler), false) = (&log_handler, stopped.aborted_calls.is_empty()) {
+        let message = format!(
+            "Aborted calls still running after the shutdown grace period: {}",
+            stopped.aborted_calls.join(", ")
+        );
+        handler
+            .0
+            .as_ref()
+            .call::<_, Value>((Level::Warn.name(), message))?;
+    }
+    stopped.into_hash(ruby)
+}
 
-    let entry = PromptEntry {
-        prompt,
-        handler: RubyHandler(BoxValue::new(handler)),
-    };
+/// Logs through `proc`, which is called with the level and the message on a
+/// pool thread. Messages logged while the pool is not running are dropped.
+fn ruby_logger(state: &Arc<ServerState>, proc: Opaque<Proc>) -> Logger {
+    let state = state.clone();
+    Logger::new(move |level, message| {
+        let _ = state.dispatcher.submit(Box::new(move |ruby| {
+            match ruby
+                .get_inner(proc)
+                .call::<_, Value>((level.name(), message))
+            {
+                Err(e) if matches!(e.error_type(), ErrorType::Jump(_)) => Err(e),
+                _ => Ok(()),
+            }
+        }));
+    })
+}
 
-    let mut map = prompts()
-        .lock()
-        .map_err(|_| Error::new(ruby.exception_runtime_error(), "prompts mutex poisoned"))?;
-    map.insert(name, entry);
-    Ok(())
+pub fn start_server(ruby: &Ruby, args: &[Value]) -> Result<RHash, Error> {
+    start(default_server(), ruby, args)
 }
 
-pub struct MyServerHandler;
+/// Queues `handler` on `dispatcher` every time one of `hangups` is received.
+/// Never completes.
+async fn reload_on_hangup(
+    mut hangups: Signals,
+    handler: Option<Opaque<Proc>>,
+    dispatcher: &Dispatcher,
+    logger: &Logger,
+) -> Infallible {
+    let Some(proc) = handler else {
+        return std::future::pending().await;
+    };
+    loop {
+        hangups.recv().await;
+        let logger = logger.clone();
+        let _ = dispatcher.submit(Box::new(move |ruby| {
+            match ruby.get_inner(proc).call::<_, Value>(()) {
+                Err(e) if matches!(e.error_type(), ErrorType::Jump(_)) => Err(e),
+                Err(e) => {
+                    logger.error(format!("Reload callback failed: {e}"));
+                    Ok(())
+                }
+                Ok(_) => Ok(()),
+            }
+        }));
+    }
+}
 
-#[async_trait]
-impl ServerHandler for MyServerHandler {
-    async fn handle_list_prompts_request(
-        &self,
-        _request: ListPromptsRequest,
-        _runtime: &dyn McpServer,
-    ) -> Result<ListPromptsResult, RpcError> {
-        let prompts = {
-            let map = prompts().lock().map_err(|_| {
-                RpcError::internal_error().with_message("prompts mutex poisoned".to_string())
-            })?;
-            map.values().map(|p| p.prompt.clone()).collect()
-        };
-        Ok(ListPromptsResult {
-            prompts,
-            meta: None,
-            next_cursor: None,
+/// Parses the optional grace period, in seconds, and run number passed to
+/// `shutdown_server`.
+fn shutdown_args(ruby: &Ruby, args: &[Value]) -> Result<(Option<Duration>, Option<u64>), Error> {
+    let args = scan_args::<(), (Option<Option<f64>>, Option<Option<u64>>), (), (), (), ()>(args)?;
+    let (grace, run) = args.optional;
+    let grace = grace
+        .flatten()
+        .map(|secs| {
+            Duration::try_from_secs_f64(secs).map_err(|_| {
+                Error::new(
+                    ruby.exception_arg_error(),
+                    format!("invalid grace period: {secs}"),
+                )
+            })
         })
+        .transpose()?;
+    Ok((grace, run.flatten()))
+}
+
+pub fn shutdown_server(ruby: &Ruby, args: &[Value]) -> Result<String, Error> {
+    let (grace, run) = shutdown_args(ruby, args)?;
+    default_server().request_shutdown(grace, run);
+    Ok("Ok".into())
+}
+
+/// Ruby handle to a [`ServerState`], exposed as `MicroMcpNative::Server`.
+#[derive(Default)]
+#[magnus::wrap(class = "MicroMcpNative::Server", free_immediately, size)]
+pub struct NativeServer(Arc<ServerState>);
+
+impl NativeServer {
+    /// Handle to the instance used by the module level functions.
+    pub fn default_instance() -> Self {
+        Self(default_server().clone())
     }
 
-    async fn handle_get_prompt_request(
-        &self,
-        request: GetPromptRequest,
-        runtime: &dyn McpServer,
-    ) -> Result<GetPromptResult, RpcError> {
-        let map = prompts().lock().map_err(|_| {
-            RpcError::internal_error().with_message("prompts mutex poisoned".to_string())
-        })?;
-        match map.get(&request.params.name) {
-            Some(entry) => {
-                let proc = *entry.handler.0.as_ref();
-                let wrapper = RubyMcpServer::new(runtime);
-                let args_value = if let Some(map) = &request.params.arguments {
-                    let json = JsonValue::Object(
-                        map.iter()
-                            .map(|(k, v)| (k.clone(), JsonValue::String(v.clone())))
-                            .collect(),
-                    );
-                    Some(
-                        crate::utils::with_gvl(|| {
-                            let ruby = Ruby::get().unwrap();
-                            json_value_to_ruby_value(&ruby, &json)
-                        })
-                        .map_err(|e: Error| {
-                            RpcError::internal_error().with_message(e.to_string())
-                        })?,
-                    )
-                } else {
-                    None
-                };
-                let msg_value: Result<Value, Error> = crate::utils::with_gvl(|| {
-                    let ruby = Ruby::get().unwrap();
-                    let args = args_value.unwrap_or_else(|| ruby.qnil().as_value());
-                    proc.call::<_, Value>((args, wrapper.clone()))
-                });
-                wrapper.invalidate();
-                let json = msg_value
-                    .map_err(|e| RpcError::internal_error().with_message(e.to_string()))
-                    .and_then(|val| {
-                        crate::utils::with_gvl(|| {
-                            let ruby = Ruby::get().unwrap();
-                            ruby_value_to_json_value(&ruby, val)
-                        })
-                        .map_err(|e: Error| RpcError::internal_error().with_message(e.to_string()))
-                    })?;
-
-                let msgs: Vec<PromptMessage> = serde_json::from_value(json)
-                    .map_err(|e| RpcError::internal_error().with_message(e.to_string()))?;
-
-                Ok(GetPromptResult {
-                    description: entry.prompt.description.clone(),
-                    messages: msgs,
-                    meta: None,
-                })
-            }
-            None => Err(RpcError::invalid_params().with_message("Unknown prompt".to_string())),
-        }
+    pub fn register_tool(
+        ruby: &Ruby,
+        rb_self: &Self,
+        name: String,
+        arg_schema: Option<Value>,
+        output_schema: Option<Value>,
+        details: Option<Value>,
+        handler: Proc,
+    ) -> Result<(), Error> {
+        rb_self
+            .0
+            .register_tool(ruby, name, arg_schema, output_schema, details, handler)
     }
-    async fn handle_list_tools_request(
-        &self,
-        _request: ListToolsRequest,
-        _runtime: &dyn McpServer,
-    ) -> Result<ListToolsResult, RpcError> {
-        let tools = {
-            let map = tools().lock().map_err(|_| {
-                RpcError::internal_error().with_message("tools mutex poisoned".to_string())
-            })?;
-            map.values().map(|t| t.tool.clone()).collect()
-        };
-        Ok(ListToolsResult {
-            tools,
-            meta: None,
-            next_cursor: None,
-        })
+
+    pub fn register_prompt(
+        ruby: &Ruby,
+        rb_self: &Self,
+        name: String,
+        description: Option<String>,
+        arguments: Option<Value>,
+        handler: Proc,
+    ) -> Result<(), Error> {
+        rb_self
+            .0
+            .register_prompt(ruby, name, description, arguments, handler)
     }
 
-    async fn handle_call_tool_request(
-        &self,
-        request: CallToolRequest,
-        runtime: &dyn McpServer,
-    ) -> Result<CallToolResult, CallToolError> {
-        let map = tools()
-            .lock()
-            .map_err(|_| CallToolError::new(std::io::Error::other("tools mutex poisoned")))?;
-        match map.get(request.tool_name()) {
-            Some(entry) => {
-                let proc = *entry.handler.0.as_ref();
-                let wrapper = RubyMcpServer::new(runtime);
-                let args_value = if let Some(map) = &request.params.arguments {
-                    let json = JsonValue::Object(map.clone());
-                    Some(
-                        crate::utils::with_gvl(|| {
-                            let ruby = Ruby::get().unwrap();
-                            json_value_to_ruby_value(&ruby, &json)
-                        })
-                        .map_err(|e: Error| {
-                            CallToolError::new(std::io::Error::other(e.to_string()))
-                        })?,
-                    )
-                } else {
-                    None
-                };
-                let text_result: Result<String, Error> = crate::utils::with_gvl(|| {
-                    let ruby = Ruby::get().unwrap();
-                    let args = args_value.unwrap_or_else(|| ruby.qnil().as_value());
-                    proc.call::<_, String>((args, wrapper.clone()))
-                });
-                wrapper.invalidate();
-                match text_result {
-                    Ok(text) => Ok(CallToolResult::text_content(vec![TextContent::new(
-                        text, None, None,
-                    )])),
-                    Err(e) => Err(CallToolError::new(std::io::Error::other(e.to_string()))),
-                }
-            }
-            None => Err(CallToolError::unknown_tool(request.tool_name().to_string())),
-        }
+    pub fn register_resource(
+        ruby: &Ruby,
+        rb_self: &Self,
+        uri: String,
+        name: String,
+        description: Option<String>,
+        mime_type: Option<String>,
+        handler: Proc,
+    ) -> Result<(), Error> {
+        rb_self
+            .0
+            .register_resource(ruby, uri, name, description, mime_type, handler)
     }
-}
 
-pub fn start_server() -> String {
-    let runtime = RUNTIME.get_or_init(|| Runtime::new().expect("Failed to create Tokio runtime"));
+    pub fn register_resource_template(
+        ruby: &Ruby,
+        rb_self: &Self,
+        uri_template: String,
+        name: String,
+        description: Option<String>,
+        mime_type: Option<String>,
+        handler: Proc,
+    ) -> Result<(), Error> {
+        rb_self.0.register_resource_template(
+            ruby,
+            uri_template,
+            name,
+            description,
+            mime_type,
+            handler,
+        )
+    }
 
-    // Reset shutdown flag for new server start
-    shutdown_flag().store(false, Ordering::Relaxed);
-
-    let _ = nogvl(|| {
-        runtime.block_on(async {
-            let server_details = InitializeResult {
-                server_info: Implementation {
-                    name: "Hello World MCP Server".to_string(),
-                    title: None,
-                    version: "0.1.0".to_string(),
-                },
-                capabilities: ServerCapabilities {
-                    tools: Some(ServerCapabilitiesTools { list_changed: None }),
-                    prompts: Some(ServerCapabilitiesPrompts { list_changed: None }),
-                    ..Default::default()
-                },
-                meta: None,
-                instructions: Some("server instructions...".to_string()),
-                protocol_version: LATEST_PROTOCOL_VERSION.to_string(),
-            };
+    pub fn notify_resource_updated(rb_self: &Self, uri: String) {
+        rb_self.0.notify_resource_updated(uri);
+    }
 
-            let handler = MyServerHandler {};
-            let transport = StdioTransport::new(TransportOptions::default())?;
-            let server: ServerRuntime =
-                server_runtime::create_server(server_details, transport, handler);
+    pub fn unregister_tool(ruby: &Ruby, rb_self: &Self, name: String) -> Result<bool, Error> {
+        rb_self.0.unregister_tool(ruby, &name)
+    }
 
-            // Use select! to wait for either server completion or shutdown signal
-            tokio::select! {
-                result = server.start() => {
-                    result
-                }
-                _ = shutdown_monitor() => {
-                    // Server was requested to shutdown
-                    Ok(())
-                }
-                _ = signal_handler() => {
-                    // System signal received
-                    Ok(())
-                }
-            }
-        })
-    });
+    pub fn unregister_prompt(ruby: &Ruby, rb_self: &Self, name: String) -> Result<bool, Error> {
+        rb_self.0.unregister_prompt(ruby, &name)
+    }
 
-    "Ok".into()
-}
+    pub fn clear_tools(ruby: &Ruby, rb_self: &Self) -> Result<(), Error> {
+        rb_self.0.clear_tools(ruby)
+    }
+
+    pub fn clear_prompts(ruby: &Ruby, rb_self: &Self) -> Result<(), Error> {
+        rb_self.0.clear_prompts(ruby)
+    }
 
-async fn signal_handler() {
-    use tokio::signal;
+    pub fn tool_names(ruby: &Ruby, rb_self: &Self) -> Result<Vec<String>, Error> {
+        rb_self.0.tool_names(ruby)
+    }
 
-    let mut sigint = signal::unix::signal(signal::unix::SignalKind::interrupt()).unwrap();
-    let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate()).unwrap();
+    pub fn prompt_names(ruby: &Ruby, rb_self: &Self) -> Result<Vec<String>, Error> {
+        rb_self.0.prompt_names(ruby)
+    }
 
-    tokio::select! {
-        _ = sigint.recv() => {},
-        _ = sigterm.recv() => {},
+    /// Begins a run for `start_server` to serve, so that it can be shut down
+    /// before it has started serving, and returns its number.
+    pub fn begin_run(ruby: &Ruby, rb_self: &Self) -> Result<u64, Error> {
+        rb_self.0.begin_run().ok_or_else(|| already_running(ruby))
     }
-}
 
-async fn shutdown_monitor() {
-    let flag = shutdown_flag();
-    loop {
-        if flag.load(Ordering::Relaxed) {
-            break;
-        }
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    pub fn start_server(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<RHash, Error> {
+        start(&rb_self.0, ruby, args)
+    }
+
+    pub fn shutdown_server(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<String, Error> {
+        let (grace, run) = shutdown_args(ruby, args)?;
+        rb_self.0.request_shutdown(grace, run);
+        Ok("Ok".into())
     }
-}
 
-pub fn shutdown_server() -> String {
-    shutdown_flag().store(true, Ordering::Relaxed);
-    "Ok".into()
+    /// Sets the proc called on SIGHUP, or removes it when `nil`. Takes effect
+    /// the next time the server starts.
+    pub fn on_reload(rb_self: &Self, handler: Option<Proc>) {
+        rb_self.0.set_reload_handler(handler);
+    }
 }
 
 #[cfg(test)]
@@ -642,6 +2232,22 @@ mod tests {
             .await?;
         let text = result.content[0].as_text_content()?.text.clone();
         assert_eq!(text, "hi");
+
+        // invalid arguments are rejected before the tool runs
+        let error = client
+            .call_tool(CallToolRequestParams {
+                name: "add_numbers".into(),
+                arguments: Some([("a".to_string(), json!("5"))].into_iter().collect()),
+            })
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("-32602"), "{error}");
+        assert!(
+            error.contains("/a: expected integer, got string"),
+            "{error}"
+        );
+        assert!(error.contains("missing required property"), "{error}");
         Ok(())
     }
 
@@ -837,4 +2443,321 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn server_identity_is_configurable() -> SdkResult<()> {
+        let transport = StdioTransport::create_with_server_launch(
+            "ruby",
+            vec![
+                "-I".into(),
+                "../../lib".into(),
+                "../../bin/mcp".into(),
+                "--name".into(),
+                "acme-tools".into(),
+                "--title".into(),
+                "Acme Tools".into(),
+                "--server-version".into(),
+                "2.3.4".into(),
+                "--instructions-file".into(),
+                "../../test/support/instructions.md".into(),
+                "../../test/support/say_hello_tool.rb".into(),
+            ],
+            None,
+            TransportOptions::default(),
+        )?;
+
+        let client_details = InitializeRequestParams {
+            capabilities: ClientCapabilities::default(),
+            client_info: Implementation {
+                name: "test-client".into(),
+                title: None,
+                version: "0.1.0".into(),
+            },
+            protocol_version: LATEST_PROTOCOL_VERSION.into(),
+        };
+
+        let client = client_runtime::create_client(client_details, transport, TestClientHandler);
+
+        client.clone().start().await?;
+
+        let server = client.server_info().expect("server info");
+        assert_eq!(server.server_info.name, "acme-tools");
+        assert_eq!(server.server_info.title.as_deref(), Some("Acme Tools"));
+        assert_eq!(server.server_info.version, "2.3.4");
+        assert_eq!(
+            server.instructions.as_deref(),
+            Some("Use say_hello_world to greet the user.\n")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_instances_are_independent() -> Result<(), Box<dyn std::error::Error>> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let socket_path =
+            std::env::temp_dir().join(format!("micro_mcp_admin_{}.sock", std::process::id()));
+        let transport = StdioTransport::create_with_server_launch(
+            "ruby",
+            vec![
+                "-I".into(),
+                "../../lib".into(),
+                "../../bin/mcp".into(),
+                "../../test/support/multiple_servers.rb".into(),
+            ],
+            Some(std::collections::HashMap::from([(
+                "ADMIN_SOCKET".to_string(),
+                socket_path.to_str().unwrap().to_string(),
+            )])),
+            TransportOptions::default(),
+        )?;
+
+        let client_details = InitializeRequestParams {
+            capabilities: ClientCapabilities::default(),
+            client_info: Implementation {
+                name: "test-client".into(),
+                title: None,
+                version: "0.1.0".into(),
+            },
+            protocol_version: LATEST_PROTOCOL_VERSION.into(),
+        };
+
+        let client = client_runtime::create_client(client_details, transport, TestClientHandler);
+        client.clone().start().await?;
+
+        let tools = client.list_tools(None).await?;
+        assert_eq!(tools.tools.len(), 1);
+        assert_eq!(tools.tools[0].name, "public_hello");
+
+        for _ in 0..100 {
+            if socket_path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let stream = tokio::net::UnixStream::connect(&socket_path).await?;
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let initialize = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {
+                "protocolVersion": LATEST_PROTOCOL_VERSION,
+                "capabilities": {},
+                "clientInfo": {"name": "test-client", "version": "0.1.0"}
+            }
+        });
+        let initialized = json!({"jsonrpc": "2.0", "method": "notifications/initialized"});
+        let list = json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"});
+        write
+            .write_all(format!("{initialize}\n{initialized}\n{list}\n").as_bytes())
+            .await?;
+        lines.next_line().await?.expect("initialize response");
+        let admin_tools: serde_json::Value =
+            serde_json::from_str(&lines.next_line().await?.expect("tools/list response"))?;
+        let names: Vec<&str> = admin_tools["result"]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool| tool["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["admin_status"]);
+
+        Ok(())
+    }
+
+    fn last_event_message(body: &str) -> serde_json::Value {
+        body.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .last()
+            .expect("no message in event stream")
+    }
+
+    #[tokio::test]
+    async fn streamable_http_transport_works() -> Result<(), Box<dyn std::error::Error>> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let _server = tokio::process::Command::new("ruby")
+            .args([
+                "-I",
+                "../../lib",
+                "../../bin/mcp",
+                "--transport",
+                "http",
+                "--port",
+                &port.to_string(),
+                "../../test/support/say_hello_tool.rb",
+            ])
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut ready = false;
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .is_ok()
+            {
+                ready = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(ready, "HTTP server did not start");
+
+        let client = reqwest::Client::new();
+        let url = format!("http://127.0.0.1:{port}/mcp");
+
+        let response = client
+            .post(&url)
+            .header("accept", "application/json, text/event-stream")
+            .body(
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": "initialize",
+                    "params": {
+                        "protocolVersion": LATEST_PROTOCOL_VERSION,
+                        "capabilities": {},
+                        "clientInfo": {"name": "test-client", "version": "0.1.0"}
+                    }
+                })
+                .to_string(),
+            )
+            .send()
+            .await?;
+        let session_id = response
+            .headers()
+            .get("mcp-session-id")
+            .expect("session id header")
+            .to_str()?
+            .to_string();
+        let init = last_event_message(&response.text().await?);
+        assert_eq!(init["id"], 1);
+        assert!(init["result"]["serverInfo"]["name"].is_string());
+
+        let response = client
+            .post(&url)
+            .header("mcp-session-id", &session_id)
+            .body(json!({"jsonrpc": "2.0", "method": "notifications/initialized"}).to_string())
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+
+        let response = client
+            .post(&url)
+            .header("mcp-session-id", &session_id)
+            .body(json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"}).to_string())
+            .send()
+            .await?;
+        let tools = last_event_message(&response.text().await?);
+        assert_eq!(tools["result"]["tools"][0]["name"], "say_hello_world");
+
+        let response = client
+            .post(&url)
+            .header("mcp-session-id", &session_id)
+            .body(
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": 3,
+                    "method": "tools/call",
+                    "params": {"name": "say_hello_world"}
+                })
+                .to_string(),
+            )
+            .send()
+            .await?;
+        let result = last_event_message(&response.text().await?);
+        assert_eq!(result["id"], 3);
+        assert_eq!(result["result"]["content"][0]["text"], "Hello World!");
+
+        let response = client
+            .delete(&url)
+            .header("mcp-session-id", &session_id)
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let response = client
+            .post(&url)
+            .header("mcp-session-id", &session_id)
+            .body(json!({"jsonrpc": "2.0", "id": 4, "method": "tools/list"}).to_string())
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unix_socket_transport_serves_multiple_connections(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let socket_path =
+            std::env::temp_dir().join(format!("micro_mcp_test_{}.sock", std::process::id()));
+        let _server = tokio::process::Command::new("ruby")
+            .args([
+                "-I",
+                "../../lib",
+                "../../bin/mcp",
+                "--transport",
+                "unix",
+                "--socket",
+                socket_path.to_str().unwrap(),
+                "../../test/support/say_hello_tool.rb",
+            ])
+            .kill_on_drop(true)
+            .spawn()?;
+
+        for _ in 0..100 {
+            if socket_path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        // each connection is a separate session on the same server process
+        for _ in 0..2 {
+            let stream = tokio::net::UnixStream::connect(&socket_path).await?;
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+
+            let initialize = json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "initialize",
+                "params": {
+                    "protocolVersion": LATEST_PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {"name": "test-client", "version": "0.1.0"}
+                }
+            });
+            write
+                .write_all(format!("{initialize}\n").as_bytes())
+                .await?;
+            let init: serde_json::Value =
+                serde_json::from_str(&lines.next_line().await?.expect("initialize response"))?;
+            assert_eq!(init["id"], 1);
+
+            let initialized = json!({"jsonrpc": "2.0", "method": "notifications/initialized"});
+            let call = json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "tools/call",
+                "params": {"name": "say_hello_world"}
+            });
+            write
+                .write_all(format!("{initialized}\n{call}\n").as_bytes())
+                .await?;
+            let result: serde_json::Value =
+                serde_json::from_str(&lines.next_line().await?.expect("tools/call response"))?;
+            assert_eq!(result["id"], 2);
+            assert_eq!(result["result"]["content"][0]["text"], "Hello World!");
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,636 @@
+//! Runtime for a single client session.
+//!
+//! The SDK's `ServerRuntime` waits for each request to be answered before it
+//! reads the next message, so one slow tool holds up every other request of
+//! its client. [`SessionRuntime`] reads newline-delimited JSON-RPC itself and
+//! answers requests concurrently, on the thread that drives
+//! [`McpServer::start`], while implementing `McpServer` so the handlers can
+//! still talk back to the client. Handlers that need to do so after the
+//! request they answer is dropped, such as Ruby code running on its own
+//! thread, use the session's [`Peer`] instead.
+
+use crate::logging::Logger;
+use async_trait::async_trait;
+use futures::stream::{BoxStream, FuturesUnordered, StreamExt};
+use rust_mcp_sdk::{
+    error::SdkResult,
+    mcp_server::ServerHandler,
+    schema::{
+        schema_utils::{
+            CallToolError, ClientJsonrpcRequest, ClientMessage, FromMessage, MessageFromServer,
+            NotificationFromClient, NotificationFromServer, RequestFromClient, RequestFromServer,
+            ResultFromClient, ResultFromServer, RpcMessage, SdkError, ServerMessage,
+        },
+        CallToolRequest, CallToolResult, ClientNotification, ClientRequest,
+        InitializeRequestParams, InitializeResult, ListToolsRequest, ProgressToken, RequestId,
+        Result as McpResult, RpcError,
+    },
+    McpDispatch, McpServer, MessageDispatcher, TransportOptions,
+};
+use serde::Deserialize;
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{oneshot, watch};
+
+type Input = Pin<Box<dyn AsyncRead + Send + Sync>>;
+type Output = Pin<Box<dyn AsyncWrite + Send + Sync>>;
+
+tokio::task_local! {
+    /// Id of the client request being answered.
+    static REQUEST: RequestId;
+}
+
+/// Returns the id of the client request the calling handler is answering, if
+/// any.
+pub fn current_request() -> Option<RequestId> {
+    REQUEST.try_with(Clone::clone).ok()
+}
+
+/// Handles the requests of a session.
+#[async_trait]
+pub trait SessionHandler: ServerHandler {
+    /// Answers `tools/list` with the result as JSON. The SDK's `Tool` only
+    /// keeps the `properties` and `required` of an input schema, so it cannot
+    /// carry the schemas tools are registered with.
+    async fn handle_list_tools(
+        &self,
+        request: ListToolsRequest,
+        runtime: &dyn McpServer,
+    ) -> Result<JsonMap<String, JsonValue>, RpcError>;
+
+    /// Answers `tools/call`. `progress_token` is the token the client asked
+    /// progress notifications to carry, if any. `cancellation` is set when
+    /// the client cancels the request, whose response is then discarded.
+    async fn handle_call_tool(
+        &self,
+        request: CallToolRequest,
+        _progress_token: Option<ProgressToken>,
+        _cancellation: Cancellation,
+        runtime: &dyn McpServer,
+    ) -> Result<CallToolResult, CallToolError> {
+        self.handle_call_tool_request(request, runtime).await
+    }
+
+    /// Notifications to send to the client unprompted, such as list changes.
+    /// They are held back until the session is initialized.
+    fn notifications(&self) -> BoxStream<'static, NotificationFromServer> {
+        futures::stream::pending().boxed()
+    }
+}
+
+/// Tells a handler that the client cancelled the request it is answering.
+#[derive(Clone)]
+pub struct Cancellation(watch::Receiver<bool>);
+
+impl Cancellation {
+    fn new() -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(false);
+        (tx, Self(rx))
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits until the request is cancelled, which may be never.
+    pub async fn cancelled(&mut self) {
+        if self.0.wait_for(|cancelled| *cancelled).await.is_err() {
+            std::future::pending().await
+        }
+    }
+}
+
+impl Default for Cancellation {
+    /// A cancellation for a request that cannot be cancelled.
+    fn default() -> Self {
+        Self(watch::channel(false).1)
+    }
+}
+
+pub struct SessionRuntime {
+    peer: Arc<Peer>,
+    handler: Box<dyn SessionHandler>,
+    /// Taken by `start`.
+    input: tokio::sync::Mutex<Option<Input>>,
+    /// Requests from the client being answered, set to true once cancelled.
+    in_flight: Mutex<HashMap<RequestId, watch::Sender<bool>>>,
+}
+
+/// The client's end of a session, for sending it requests and notifications.
+/// Unlike the runtime handlers are passed, it can be kept after the request
+/// being answered is dropped; once the session has ended, requests and
+/// notifications fail with a connection closed error.
+pub struct Peer {
+    server_details: InitializeResult,
+    client_details: RwLock<Option<InitializeRequestParams>>,
+    output: tokio::sync::Mutex<Output>,
+    /// Requests sent to the client, waiting for its response.
+    pending: Mutex<HashMap<RequestId, oneshot::Sender<ClientMessage>>>,
+    /// The client request each request waiting for the client was sent while
+    /// answering, if any.
+    related: Mutex<HashMap<RequestId, RequestId>>,
+    next_request_id: AtomicI64,
+    request_timeout: Duration,
+    /// Set once the session has ended.
+    closed: AtomicBool,
+    /// Receives the messages handlers write with `stderr_message`.
+    logger: Logger,
+    /// Never set; `request` and `send_notification` write messages themselves.
+    sender: tokio::sync::RwLock<Option<MessageDispatcher<ClientMessage>>>,
+}
+
+impl SessionRuntime {
+    /// Creates a session whose requests are answered by the handler
+    /// `new_handler` returns for the session's peer.
+    pub fn new<H: SessionHandler>(
+        server_details: InitializeResult,
+        logger: Logger,
+        new_handler: impl FnOnce(Arc<Peer>) -> H,
+        input: impl AsyncRead + Send + Sync + 'static,
+        output: impl AsyncWrite + Send + Sync + 'static,
+    ) -> Self {
+        let peer = Arc::new(Peer {
+            server_details,
+            client_details: RwLock::new(None),
+            output: tokio::sync::Mutex::new(Box::pin(output)),
+            pending: Mutex::default(),
+            related: Mutex::default(),
+            next_request_id: AtomicI64::new(0),
+            request_timeout: TransportOptions::default().timeout,
+            closed: AtomicBool::new(false),
+            logger,
+            sender: tokio::sync::RwLock::new(None),
+        });
+        Self {
+            handler: Box::new(new_handler(peer.clone())),
+            peer,
+            input: tokio::sync::Mutex::new(Some(Box::pin(input))),
+            in_flight: Mutex::default(),
+        }
+    }
+
+    /// The client's end of the session.
+    pub fn peer(&self) -> Arc<Peer> {
+        self.peer.clone()
+    }
+
+    async fn send(&self, message: MessageFromServer, id: Option<RequestId>) -> SdkResult<()> {
+        self.peer.send(message, id).await
+    }
+
+    /// Tracks request `id` until it is answered, so the client can cancel it.
+    fn track(&self, id: &RequestId) -> Cancellation {
+        let (cancel, cancellation) = Cancellation::new();
+        self.in_flight.lock().unwrap().insert(id.clone(), cancel);
+        cancellation
+    }
+
+    /// Answers `request`, unless the client cancels it meanwhile.
+    async fn respond(
+        &self,
+        request: ClientJsonrpcRequest,
+        progress_token: Option<ProgressToken>,
+        cancellation: Cancellation,
+    ) -> SdkResult<()> {
+        let result = REQUEST
+            .scope(
+                request.id.clone(),
+                self.handle_request(request.request, progress_token, cancellation),
+            )
+            .await;
+        let cancelled = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&request.id)
+            .is_some_and(|cancel| *cancel.borrow());
+        if cancelled {
+            return Ok(());
+        }
+        let response = match result {
+            Ok(result) => MessageFromServer::ResultFromServer(result),
+            Err(error) => MessageFromServer::Error(error),
+        };
+        self.send(response, Some(request.id)).await
+    }
+
+    /// Hands a response or error from the client to the request waiting for
+    /// it. Errors that do not belong to a request go to the handler.
+    async fn route_response(&self, message: ClientMessage) -> SdkResult<()> {
+        let waiting = message
+            .request_id()
+            .and_then(|id| self.peer.pending.lock().unwrap().remove(id));
+        match (waiting, message) {
+            (Some(tx), message) => {
+                let _ = tx.send(message);
+            }
+            (None, ClientMessage::Error(error)) => {
+                self.handler.handle_error(error.error, self).await?;
+            }
+            (None, _) => {}
+        }
+        Ok(())
+    }
+
+    async fn handle_request(
+        &self,
+        request: RequestFromClient,
+        progress_token: Option<ProgressToken>,
+        cancellation: Cancellation,
+    ) -> Result<ResultFromServer, RpcError> {
+        let handler = &self.handler;
+        let request = match request {
+            RequestFromClient::ClientRequest(request) => request,
+            RequestFromClient::CustomRequest(value) => {
+                return handler
+                    .handle_custom_request(value, self)
+                    .await
+                    .map(Into::into)
+            }
+        };
+        match request {
+            ClientRequest::InitializeRequest(request) => handler
+                .handle_initialize_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::PingRequest(request) => handler
+                .handle_ping_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::ListResourcesRequest(request) => handler
+                .handle_list_resources_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::ListResourceTemplatesRequest(request) => handler
+                .handle_list_resource_templates_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::ReadResourceRequest(request) => handler
+                .handle_read_resource_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::SubscribeRequest(request) => handler
+                .handle_subscribe_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::UnsubscribeRequest(request) => handler
+                .handle_unsubscribe_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::ListPromptsRequest(request) => handler
+                .handle_list_prompts_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::GetPromptRequest(request) => handler
+                .handle_get_prompt_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::ListToolsRequest(request) => handler
+                .handle_list_tools(request, self)
+                .await
+                .map(|result| {
+                    McpResult {
+                        meta: None,
+                        extra: Some(result),
+                    }
+                    .into()
+                }),
+            // Tool failures are reported in the result rather than as errors,
+            // unless the handler fails with a protocol error, e.g. for
+            // invalid arguments.
+            ClientRequest::CallToolRequest(request) => {
+                let result = handler
+                    .handle_call_tool(request, progress_token, cancellation, self)
+                    .await;
+                match result {
+                    Ok(result) => Ok(result.into()),
+                    Err(CallToolError(error)) => match error.downcast::<RpcError>() {
+                        Ok(error) => Err(*error),
+                        Err(error) => Ok(CallToolResult::from(CallToolError(error)).into()),
+                    },
+                }
+            }
+            ClientRequest::SetLevelRequest(request) => handler
+                .handle_set_level_request(request, self)
+                .await
+                .map(Into::into),
+            ClientRequest::CompleteRequest(request) => handler
+                .handle_complete_request(request, self)
+                .await
+                .map(Into::into),
+        }
+    }
+
+    async fn handle_notification(
+        &self,
+        notification: NotificationFromClient,
+    ) -> Result<(), RpcError> {
+        let handler = &self.handler;
+        let notification = match notification {
+            NotificationFromClient::ClientNotification(notification) => notification,
+            NotificationFromClient::CustomNotification(value) => {
+                return handler.handle_custom_notification(value).await;
+            }
+        };
+        match notification {
+            ClientNotification::CancelledNotification(notification) => {
+                if let Some(cancel) = self
+                    .in_flight
+                    .lock()
+                    .unwrap()
+                    .get(&notification.params.request_id)
+                {
+                    cancel.send_replace(true);
+                }
+                handler
+                    .handle_cancelled_notification(notification, self)
+                    .await
+            }
+            ClientNotification::InitializedNotification(notification) => {
+                handler
+                    .handle_initialized_notification(notification, self)
+                    .await?;
+                handler.on_initialized(self).await;
+                Ok(())
+            }
+            ClientNotification::ProgressNotification(notification) => {
+                handler
+                    .handle_progress_notification(notification, self)
+                    .await
+            }
+            ClientNotification::RootsListChangedNotification(notification) => {
+                handler
+                    .handle_roots_list_changed_notification(notification, self)
+                    .await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl McpServer for SessionRuntime {
+    /// Serves the session until the client closes its input. Requests are
+    /// answered concurrently once the session is initialized; those still
+    /// running when the input closes are finished first.
+    async fn start(&self) -> SdkResult<()> {
+        let input = self.input.lock().await.take().ok_or_else(|| {
+            RpcError::internal_error().with_message("session already started".to_string())
+        })?;
+        self.handler.on_server_started(self).await;
+
+        let mut lines = BufReader::new(input).lines();
+        let mut requests = FuturesUnordered::new();
+        let mut notifications = self.handler.notifications();
+        let result = loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let line = match line {
+                        Ok(Some(line)) => line,
+                        Ok(None) => break Ok(()),
+                        Err(e) => break Err(e.into()),
+                    };
+                    // malformed messages are skipped, like the SDK transports do
+                    let Ok(message) = serde_json::from_str::<JsonValue>(&line) else {
+                        continue;
+                    };
+                    // the SDK's request params drop `_meta`, so read the token here
+                    let progress_token = message
+                        .pointer("/params/_meta/progressToken")
+                        .and_then(|token| ProgressToken::deserialize(token).ok());
+                    let Ok(message) = serde_json::from_value::<ClientMessage>(message) else {
+                        continue;
+                    };
+                    match message {
+                        // Requests before initialization are answered in order. A failed
+                        // initialize, e.g. an unsupported protocol version, ends the session
+                        // once the client has its error.
+                        ClientMessage::Request(request) if !self.is_initialized() => {
+                            let initialize = matches!(
+                                request.request,
+                                RequestFromClient::ClientRequest(ClientRequest::InitializeRequest(_))
+                            );
+                            let result = self
+                                .handle_request(request.request, progress_token, Cancellation::default())
+                                .await;
+                            let (response, failure) = match result {
+                                Ok(result) => (MessageFromServer::ResultFromServer(result), None),
+                                Err(error) => (
+                                    MessageFromServer::Error(error.clone()),
+                                    initialize.then_some(error),
+                                ),
+                            };
+                            if let Err(e) = self.send(response, Some(request.id)).await {
+                                break Err(e);
+                            }
+                            if let Some(error) = failure {
+                                break Err(error.into());
+                            }
+                        }
+                        ClientMessage::Request(request) => {
+                            let cancellation = self.track(&request.id);
+                            requests.push(self.respond(request, progress_token, cancellation));
+                        }
+                        ClientMessage::Notification(notification) => {
+                            if let Err(e) = self.handle_notification(notification.notification).await {
+                                break Err(e.into());
+                            }
+                        }
+                        message => {
+                            if let Err(e) = self.route_response(message).await {
+                                break Err(e);
+                            }
+                        }
+                    }
+                }
+                Some(result) = requests.next() => {
+                    if let Err(e) = result {
+                        break Err(e);
+                    }
+                }
+                Some(notification) = notifications.next(), if self.is_initialized() => {
+                    if let Err(e) = self.send_notification(notification).await {
+                        break Err(e);
+                    }
+                }
+            }
+        };
+
+        // nothing will answer the requests still waiting for the client
+        self.peer.pending.lock().unwrap().clear();
+        if result.is_ok() {
+            while let Some(result) = requests.next().await {
+                if let Err(e) = result {
+                    self.peer.close();
+                    return Err(e);
+                }
+            }
+        }
+        self.peer.close();
+        result
+    }
+
+    fn set_client_details(&self, client_details: InitializeRequestParams) -> SdkResult<()> {
+        self.peer.set_client_details(client_details)
+    }
+
+    fn server_info(&self) -> &InitializeResult {
+        self.peer.server_info()
+    }
+
+    fn client_info(&self) -> Option<InitializeRequestParams> {
+        self.peer.client_info()
+    }
+
+    async fn sender(&self) -> &tokio::sync::RwLock<Option<MessageDispatcher<ClientMessage>>>
+    where
+        MessageDispatcher<ClientMessage>: McpDispatch<ClientMessage, MessageFromServer>,
+    {
+        &self.peer.sender
+    }
+
+    async fn request(
+        &self,
+        request: RequestFromServer,
+        timeout: Option<Duration>,
+    ) -> SdkResult<ResultFromClient> {
+        self.peer.request(request, timeout).await
+    }
+
+    async fn send_notification(&self, notification: NotificationFromServer) -> SdkResult<()> {
+        self.peer.send_notification(notification).await
+    }
+
+    async fn stderr_message(&self, message: String) -> SdkResult<()> {
+        self.peer.stderr_message(message).await
+    }
+}
+
+impl Peer {
+    /// Writes `message` to the client as a single line.
+    async fn send(&self, message: MessageFromServer, id: Option<RequestId>) -> SdkResult<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(SdkError::connection_closed().into());
+        }
+        let message = ServerMessage::from_message(message, id)?;
+        let mut line = serde_json::to_string(&message)
+            .map_err(|e| RpcError::internal_error().with_message(e.to_string()))?;
+        line.push('\n');
+        let mut output = self.output.lock().await;
+        output.write_all(line.as_bytes()).await?;
+        output.flush().await?;
+        Ok(())
+    }
+
+    /// Fails the requests waiting for the client, and any sent from now on.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// Sends `request` to the client like `request` does, noting that it is
+    /// sent while answering client request `related`, so that a transport
+    /// can deliver it along with that request's response.
+    pub async fn request_for(
+        &self,
+        related: Option<RequestId>,
+        request: RequestFromServer,
+        timeout: Option<Duration>,
+    ) -> SdkResult<ResultFromClient> {
+        let id = RequestId::Integer(self.next_request_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), tx);
+        if let Some(related) = related {
+            self.related.lock().unwrap().insert(id.clone(), related);
+        }
+
+        let sent = self
+            .send(
+                MessageFromServer::RequestFromServer(request),
+                Some(id.clone()),
+            )
+            .await;
+        let timeout = timeout.unwrap_or(self.request_timeout);
+        let response = match sent {
+            Ok(()) => tokio::time::timeout(timeout, rx).await,
+            Err(e) => {
+                self.pending.lock().unwrap().remove(&id);
+                self.related.lock().unwrap().remove(&id);
+                return Err(e);
+            }
+        };
+        self.pending.lock().unwrap().remove(&id);
+        self.related.lock().unwrap().remove(&id);
+
+        match response {
+            Ok(Ok(ClientMessage::Response(response))) => Ok(response.result),
+            Ok(Ok(ClientMessage::Error(error))) => Err(error.error.into()),
+            Ok(Ok(_)) => Err(RpcError::internal_error()
+                .with_message("unexpected message in response to a request".to_string())
+                .into()),
+            Ok(Err(_)) => Err(SdkError::connection_closed().into()),
+            Err(_) => Err(SdkError::request_timeout(timeout.as_millis()).into()),
+        }
+    }
+
+    /// The client request that request `id`, sent to the client and not yet
+    /// answered, was sent while answering.
+    pub fn related_request(&self, id: &RequestId) -> Option<RequestId> {
+        self.related.lock().unwrap().get(id).cloned()
+    }
+}
+
+#[async_trait]
+impl McpServer for Peer {
+    /// Sessions are started through their [`SessionRuntime`].
+    async fn start(&self) -> SdkResult<()> {
+        Err(RpcError::internal_error()
+            .with_message("a peer cannot be started".to_string())
+            .into())
+    }
+
+    fn set_client_details(&self, client_details: InitializeRequestParams) -> SdkResult<()> {
+        *self.client_details.write().unwrap() = Some(client_details);
+        Ok(())
+    }
+
+    fn server_info(&self) -> &InitializeResult {
+        &self.server_details
+    }
+
+    fn client_info(&self) -> Option<InitializeRequestParams> {
+        self.client_details.read().unwrap().clone()
+    }
+
+    async fn sender(&self) -> &tokio::sync::RwLock<Option<MessageDispatcher<ClientMessage>>>
+    where
+        MessageDispatcher<ClientMessage>: McpDispatch<ClientMessage, MessageFromServer>,
+    {
+        &self.sender
+    }
+
+    async fn request(
+        &self,
+        request: RequestFromServer,
+        timeout: Option<Duration>,
+    ) -> SdkResult<ResultFromClient> {
+        self.request_for(current_request(), request, timeout).await
+    }
+
+    async fn send_notification(&self, notification: NotificationFromServer) -> SdkResult<()> {
+        self.send(
+            MessageFromServer::NotificationFromServer(notification),
+            None,
+        )
+        .await
+    }
+
+    async fn stderr_message(&self, message: String) -> SdkResult<()> {
+        self.logger.info(message);
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Process signals the server listens for.
+//!
+//! Tokio installs a process wide handler the first time a signal is listened
+//! for, so nothing is registered unless the server is configured to handle
+//! that signal.
+
+use futures::future::select_all;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Signal names accepted in the configuration, with or without the `SIG`
+/// prefix.
+const SIGNALS: &[(&str, SignalKind)] = &[
+    ("SIGINT", SignalKind::interrupt()),
+    ("SIGTERM", SignalKind::terminate()),
+    ("SIGHUP", SignalKind::hangup()),
+    ("SIGQUIT", SignalKind::quit()),
+    ("SIGUSR1", SignalKind::user_defined1()),
+    ("SIGUSR2", SignalKind::user_defined2()),
+];
+
+/// Returns the canonical name of `name`, e.g. `"SIGINT"` for `"INT"`.
+pub fn canonical_name(name: &str) -> Option<&'static str> {
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .map(|(canonical, _)| *canonical)
+        .find(|canonical| &canonical[3..] == name)
+}
+
+/// A set of signals to wait for.
+pub struct Signals {
+    streams: Vec<(&'static str, Signal)>,
+}
+
+impl Signals {
+    /// Starts listening for `names`, which must have been checked with
+    /// [`canonical_name`]. Must be called within the Tokio runtime.
+    pub fn new<S: AsRef<str>>(names: &[S]) -> std::io::Result<Self> {
+        let mut streams = Vec::new();
+        for name in names {
+            let Some(canonical) = canonical_name(name.as_ref()) else {
+                continue;
+            };
+            let (_, kind) = SIGNALS.iter().find(|(n, _)| *n == canonical).unwrap();
+            streams.push((canonical, signal(*kind)?));
+        }
+        Ok(Self { streams })
+    }
+
+    /// Waits for the next signal and returns its name. Never completes when
+    /// the set is empty.
+    pub async fn recv(&mut self) -> &'static str {
+        if self.streams.is_empty() {
+            return std::future::pending().await;
+        }
+        let waits = self.streams.iter_mut().map(|(name, stream)| {
+            Box::pin(async move {
+                stream.recv().await;
+                *name
+            })
+        });
+        select_all(waits).await.0
+    }
+}
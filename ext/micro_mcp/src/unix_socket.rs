@@ -0,0 +1,127 @@
+//! MCP over a Unix domain socket.
+//!
+//! Every accepted connection is an independent client session speaking
+//! newline-delimited JSON-RPC, the same framing as the stdio transport, so a
+//! long running server can be attached to by any number of editors without
+//! starting a new Ruby process for each of them.
+
+use crate::duplex::{self, AbortOnDrop, ClientEnds};
+use crate::logging::Logger;
+use crate::session::{Peer, SessionHandler, SessionRuntime};
+use rust_mcp_sdk::schema::InitializeResult;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+/// Removes the socket file once the server stops listening.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Serves MCP on the Unix domain socket at `path` until the returned future
+/// is dropped. Only fails when the socket cannot be created.
+pub async fn serve<H, F>(
+    path: &str,
+    server_details: InitializeResult,
+    logger: Logger,
+    new_handler: F,
+) -> std::io::Result<()>
+where
+    H: SessionHandler,
+    F: Fn(Arc<Peer>) -> H + Send + Sync + 'static,
+{
+    remove_stale_socket(Path::new(path))?;
+    let listener = UnixListener::bind(path)?;
+    let _socket_file = SocketFile(PathBuf::from(path));
+    logger.info(format!("MCP server listening on unix socket {path}"));
+
+    let (runtimes_tx, runtimes_rx) = mpsc::unbounded_channel();
+    let accept = AbortOnDrop(tokio::spawn(accept_loop(
+        listener,
+        Arc::new(server_details),
+        logger,
+        new_handler,
+        runtimes_tx,
+    )));
+    duplex::run_sessions(accept, runtimes_rx, |()| {}).await
+}
+
+/// A socket left behind by a server that did not shut down cleanly would make
+/// `bind` fail, so it is removed. A socket that a server still listens on, or
+/// any other kind of file, is left alone.
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+                Err(e) => Err(e),
+                Ok(_) => Err(std::io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("another server is listening on {}", path.display()),
+                )),
+            }
+        }
+        Ok(_) => Err(std::io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn accept_loop<H, F>(
+    listener: UnixListener,
+    server_details: Arc<InitializeResult>,
+    logger: Logger,
+    new_handler: F,
+    runtimes: mpsc::UnboundedSender<((), SessionRuntime)>,
+) -> std::io::Result<()>
+where
+    H: SessionHandler,
+    F: Fn(Arc<Peer>) -> H + Send + Sync + 'static,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                duplex::accept_failed(e, &logger).await;
+                continue;
+            }
+        };
+        let (server, ends) =
+            duplex::create_session((*server_details).clone(), logger.clone(), &new_handler);
+        tokio::spawn(pump(stream, ends));
+        if runtimes.send(((), server)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Copies bytes between a connection and its session until either side
+/// closes.
+async fn pump(stream: UnixStream, ends: ClientEnds) {
+    let (mut read, mut write) = stream.into_split();
+    let ClientEnds {
+        mut input,
+        mut output,
+    } = ends;
+    let inbound = async {
+        let _ = tokio::io::copy(&mut read, &mut input).await;
+        // closing the input ends the session's runtime
+        let _ = input.shutdown().await;
+    };
+    let outbound = async {
+        let _ = tokio::io::copy(&mut output, &mut write).await;
+        let _ = write.shutdown().await;
+    };
+    tokio::join!(inbound, outbound);
+}
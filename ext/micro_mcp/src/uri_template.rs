@@ -0,0 +1,415 @@
+//! RFC 6570 URI templates for resource templates.
+//!
+//! Templates are used the other way around from expansion: the URI a client
+//! reads is matched against a template and the values of its variables are
+//! extracted. All four levels of the syntax are accepted. Matching is
+//! necessarily approximate where expansion loses information: a variable
+//! that is not exploded receives its value as a single string, prefix
+//! lengths (`{var:3}`) are not enforced, and variables of form-style
+//! expressions (`{?limit,offset}`) may appear in any order. Expressions that
+//! expand without a prefix (`{var}`, `{+var}`) must match one character at
+//! least, so `config://{name}` does not match `config://`; the others may be
+//! left out of the URI entirely.
+
+use regex::Regex;
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+/// Characters that are never percent encoded.
+const UNRESERVED: &str = r"A-Za-z0-9\-._~";
+
+/// Characters that `+` and `#` expressions leave unencoded on top of the
+/// unreserved ones.
+const RESERVED: &str = r":/?#\[\]@!$&'()*+,;=";
+
+/// A parsed template that URIs can be matched against.
+#[derive(Debug)]
+pub struct UriTemplate {
+    expressions: Vec<Expression>,
+    regex: Regex,
+}
+
+#[derive(Debug)]
+struct Expression {
+    operator: Operator,
+    variables: Vec<Variable>,
+}
+
+#[derive(Debug)]
+struct Variable {
+    name: String,
+    explode: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Simple,
+    Reserved,
+    Fragment,
+    Label,
+    Path,
+    PathParameter,
+    Query,
+    QueryContinuation,
+}
+
+impl Operator {
+    fn parse(c: char) -> Option<Self> {
+        Some(match c {
+            '+' => Self::Reserved,
+            '#' => Self::Fragment,
+            '.' => Self::Label,
+            '/' => Self::Path,
+            ';' => Self::PathParameter,
+            '?' => Self::Query,
+            '&' => Self::QueryContinuation,
+            _ => return None,
+        })
+    }
+
+    /// The text an expansion starts with.
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::Simple | Self::Reserved => "",
+            Self::Fragment => "#",
+            Self::Label => ".",
+            Self::Path => "/",
+            Self::PathParameter => ";",
+            Self::Query => "?",
+            Self::QueryContinuation => "&",
+        }
+    }
+
+    /// The text between the values of an expansion.
+    fn separator(self) -> char {
+        match self {
+            Self::Simple | Self::Reserved | Self::Fragment => ',',
+            Self::Label => '.',
+            Self::Path => '/',
+            Self::PathParameter => ';',
+            Self::Query | Self::QueryContinuation => '&',
+        }
+    }
+
+    /// Whether values are expanded as `name=value` pairs.
+    fn named(self) -> bool {
+        matches!(
+            self,
+            Self::PathParameter | Self::Query | Self::QueryContinuation
+        )
+    }
+
+    /// The regex class of the characters an expansion may contain, besides
+    /// percent encoded ones.
+    fn characters(self) -> String {
+        match self {
+            Self::Reserved | Self::Fragment => format!("{UNRESERVED}{RESERVED}"),
+            Self::Simple | Self::Label => format!("{UNRESERVED},"),
+            Self::Path => format!("{UNRESERVED},/"),
+            Self::PathParameter => format!("{UNRESERVED},;="),
+            Self::Query | Self::QueryContinuation => format!("{UNRESERVED},&="),
+        }
+    }
+}
+
+impl UriTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut pattern = String::from("^");
+        let mut expressions = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let (literal, after) = rest.split_at(start);
+            if literal.contains('}') {
+                return Err(format!("unmatched '}}' in {template}"));
+            }
+            pattern.push_str(&regex::escape(literal));
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unclosed expression in {template}"))?;
+            let expression =
+                parse_expression(&after[1..end]).map_err(|e| format!("{e} in {template}"))?;
+            let operator = expression.operator;
+            let value = format!("(?:[{}]|%[0-9A-Fa-f]{{2}})", operator.characters());
+            if operator.prefix().is_empty() {
+                // without a prefix an empty value could not be told apart
+                // from a missing one, so one character at least is required
+                pattern.push_str(&format!("({value}+?)"));
+            } else {
+                pattern.push_str(&format!(
+                    "(?:{}({value}*?))?",
+                    regex::escape(operator.prefix())
+                ));
+            }
+            expressions.push(expression);
+            rest = &after[end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("unmatched '}}' in {template}"));
+        }
+        pattern.push_str(&regex::escape(rest));
+        pattern.push('$');
+        let regex = Regex::new(&pattern).map_err(|e| e.to_string())?;
+        Ok(Self { expressions, regex })
+    }
+
+    /// Matches `uri` against the template and returns the values of the
+    /// variables it defines: strings, arrays for exploded lists and objects
+    /// for exploded `name=value` pairs. Returns `None` when the URI does not
+    /// match.
+    pub fn match_uri(&self, uri: &str) -> Option<JsonMap<String, JsonValue>> {
+        let captures = self.regex.captures(uri)?;
+        let mut variables = JsonMap::new();
+        for (expression, capture) in self.expressions.iter().zip(captures.iter().skip(1)) {
+            if let Some(capture) = capture {
+                expression.extract(capture.as_str(), &mut variables)?;
+            }
+        }
+        Some(variables)
+    }
+}
+
+fn parse_expression(body: &str) -> Result<Expression, String> {
+    let mut chars = body.chars();
+    let (operator, list) = match chars.next().map(|c| (c, Operator::parse(c))) {
+        Some((_, Some(operator))) => (operator, chars.as_str()),
+        Some(('=' | ',' | '!' | '@' | '|', None)) => {
+            return Err(format!("reserved operator in {{{body}}}"))
+        }
+        _ => (Operator::Simple, body),
+    };
+    let variables = list
+        .split(',')
+        .map(|spec| {
+            let (name, explode) = match spec.strip_suffix('*') {
+                Some(name) => (name, true),
+                None => match spec.split_once(':') {
+                    Some((name, length)) if matches!(length.parse::<u16>(), Ok(1..=9999)) => {
+                        (name, false)
+                    }
+                    Some(_) => return Err(format!("invalid prefix in {{{body}}}")),
+                    None => (spec, false),
+                },
+            };
+            let valid = !name.is_empty()
+                && name.split('.').all(|part| {
+                    !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                });
+            if !valid {
+                return Err(format!("invalid variable name {name:?}"));
+            }
+            Ok(Variable {
+                name: name.to_string(),
+                explode,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Expression {
+        operator,
+        variables,
+    })
+}
+
+impl Expression {
+    /// Adds the variables defined by `expansion`, the text an expansion of
+    /// this expression produced without its prefix, to `variables`.
+    fn extract(&self, expansion: &str, variables: &mut JsonMap<String, JsonValue>) -> Option<()> {
+        let separator = self.operator.separator();
+        if self.operator.named() {
+            for pair in expansion.split(separator).filter(|pair| !pair.is_empty()) {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let (name, value) = (decode(name)?, JsonValue::String(decode(value)?));
+                match self.variables.iter().find(|variable| variable.name == name) {
+                    Some(variable) if variable.explode => append(variables, name, value),
+                    Some(_) => {
+                        variables.insert(name, value);
+                    }
+                    // `name=value` pairs of an exploded associative array
+                    None => {
+                        if let Some(variable) = self.variables.iter().find(|v| v.explode) {
+                            let pairs = variables
+                                .entry(variable.name.clone())
+                                .or_insert_with(|| JsonValue::Object(JsonMap::new()));
+                            if let JsonValue::Object(pairs) = pairs {
+                                pairs.insert(name, value);
+                            }
+                        }
+                    }
+                }
+            }
+            return Some(());
+        }
+
+        // values are assigned in order, the last variable taking the rest
+        let mut values = expansion.splitn(self.variables.len(), separator);
+        for variable in &self.variables {
+            let Some(value) = values.next() else {
+                break;
+            };
+            let value = if variable.explode {
+                let items = value
+                    .split(separator)
+                    .map(|item| decode(item).map(JsonValue::String))
+                    .collect::<Option<_>>()?;
+                JsonValue::Array(items)
+            } else {
+                JsonValue::String(decode(value)?)
+            };
+            variables.insert(variable.name.clone(), value);
+        }
+        Some(())
+    }
+}
+
+/// Adds `value` to the array of values of exploded variable `name`.
+fn append(variables: &mut JsonMap<String, JsonValue>, name: String, value: JsonValue) {
+    let values = variables
+        .entry(name)
+        .or_insert_with(|| JsonValue::Array(Vec::new()));
+    if let JsonValue::Array(values) = values {
+        values.push(value);
+    }
+}
+
+/// Percent decodes `text`, or returns `None` if it does not decode to UTF-8.
+fn decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail) {
+            (b'%', [_, _, tail @ ..]) => {
+                let hex = std::str::from_utf8(&rest[1..3]).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = tail;
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UriTemplate;
+    use serde_json::{json, Value as JsonValue};
+
+    fn variables(template: &str, uri: &str) -> Option<JsonValue> {
+        let template = UriTemplate::parse(template).unwrap();
+        template.match_uri(uri).map(JsonValue::Object)
+    }
+
+    #[test]
+    fn simple_expressions() {
+        assert_eq!(
+            variables("config://{name}", "config://app"),
+            Some(json!({"name": "app"}))
+        );
+        assert_eq!(variables("config://{name}", "config://"), None);
+        assert_eq!(variables("config://{name}", "config://a/b"), None);
+        assert_eq!(
+            variables("map://{x},{y}", "map://1,2"),
+            Some(json!({"x": "1", "y": "2"}))
+        );
+        assert_eq!(
+            variables("map://{x,y}", "map://1,2"),
+            Some(json!({"x": "1", "y": "2"}))
+        );
+        assert_eq!(
+            variables("repo://{owner}/{repo}", "repo://rust-lang/regex"),
+            Some(json!({"owner": "rust-lang", "repo": "regex"}))
+        );
+        assert_eq!(variables("{var:3}", "abc"), Some(json!({"var": "abc"})));
+        assert_eq!(variables("static://x", "static://x"), Some(json!({})));
+        assert_eq!(variables("static://x", "static://y"), None);
+    }
+
+    #[test]
+    fn reserved_and_fragment_expressions() {
+        assert_eq!(
+            variables("file:///{+path}", "file:///etc/app.yml"),
+            Some(json!({"path": "etc/app.yml"}))
+        );
+        assert_eq!(variables("file:///{+path}", "file:///"), None);
+        assert_eq!(
+            variables("doc://page{#section}", "doc://page#intro"),
+            Some(json!({"section": "intro"}))
+        );
+        assert_eq!(
+            variables("doc://page{#section}", "doc://page"),
+            Some(json!({}))
+        );
+    }
+
+    #[test]
+    fn path_expressions() {
+        assert_eq!(
+            variables("tree://root{/segments*}", "tree://root/a/b/c"),
+            Some(json!({"segments": ["a", "b", "c"]}))
+        );
+        assert_eq!(
+            variables("tree://root{/first,rest}", "tree://root/a/b/c"),
+            Some(json!({"first": "a", "rest": "b/c"}))
+        );
+        assert_eq!(
+            variables("tree://root{/segments*}", "tree://root"),
+            Some(json!({}))
+        );
+        assert_eq!(
+            variables("file://{name}{.ext}", "file://report.json"),
+            Some(json!({"name": "report", "ext": "json"}))
+        );
+        assert_eq!(
+            variables("item://x{;id}", "item://x;id=5"),
+            Some(json!({"id": "5"}))
+        );
+    }
+
+    #[test]
+    fn query_expressions() {
+        let template = "db://tables/{table}/rows{?limit,offset}";
+        assert_eq!(
+            variables(template, "db://tables/users/rows?offset=20&limit=10"),
+            Some(json!({"table": "users", "limit": "10", "offset": "20"}))
+        );
+        assert_eq!(
+            variables(template, "db://tables/users/rows"),
+            Some(json!({"table": "users"}))
+        );
+        assert_eq!(variables(template, "db://tables/a/b/rows"), None);
+        assert_eq!(
+            variables("search://{?tags*}", "search://?tags=a&tags=b"),
+            Some(json!({"tags": ["a", "b"]}))
+        );
+        assert_eq!(
+            variables("search://{?params*}", "search://?a=1&b=2"),
+            Some(json!({"params": {"a": "1", "b": "2"}}))
+        );
+        assert_eq!(
+            variables("search://{?q}{&page}", "search://?q=rust&page=2"),
+            Some(json!({"q": "rust", "page": "2"}))
+        );
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(
+            variables("file:///{+path}", "file:///my%20docs/caf%C3%A9.txt"),
+            Some(json!({"path": "my docs/café.txt"}))
+        );
+        assert_eq!(
+            variables("search://{?q}", "search://?q=a%26b"),
+            Some(json!({"q": "a&b"}))
+        );
+        // not UTF-8 once decoded
+        assert_eq!(variables("config://{name}", "config://%FF"), None);
+        assert_eq!(variables("config://{name}", "config://%zz"), None);
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in ["x{", "x}", "x{}", "{=x}", "{a b}", "{x:0}", "{x:a}", "{x,}"] {
+            assert!(UriTemplate::parse(template).is_err(), "{template}");
+        }
+    }
+}
@@ -1,5 +1,7 @@
-use rb_sys::{rb_thread_call_with_gvl, rb_thread_call_without_gvl};
-use std::{ffi::c_void, mem::MaybeUninit, ptr::null_mut};
+use magnus::{Error, Ruby};
+use rb_sys::{rb_nogvl, rb_thread_call_with_gvl, rb_thread_call_without_gvl, RB_NOGVL_INTR_FAIL};
+use std::{ffi::c_void, future::Future, mem::MaybeUninit, pin::pin, ptr::null_mut};
+use tokio::{runtime::Handle, sync::Notify};
 
 unsafe extern "C" fn call_without_gvl<F, R>(arg: *mut c_void) -> *mut c_void
 where
@@ -25,6 +27,69 @@ where
     }
 }
 
+unsafe extern "C" fn call_unblock<U>(arg: *mut c_void)
+where
+    U: Fn(),
+{
+    let unblock = arg as *const U;
+    unsafe { (*unblock)() }
+}
+
+/// Like [`nogvl`], for work that Ruby may need to interrupt, e.g. with
+/// `Thread#kill` or `Thread#raise`. `unblock` is then called from another
+/// thread and must make `func` return `None` soon. The interrupt is handled
+/// once `func` has returned: one that raises is returned as the error,
+/// otherwise `func` is called again until it returns a value.
+pub fn nogvl_interruptible<F, R, U>(mut func: F, unblock: U) -> Result<R, Error>
+where
+    F: FnMut() -> Option<R>,
+    U: Fn() + Sync,
+{
+    let ruby = Ruby::get().unwrap();
+    loop {
+        // stays `None` when an interrupt is already pending and `func` is
+        // not called at all
+        let mut result = MaybeUninit::new(None);
+        let mut arg = (&mut func, &mut result);
+        let arg_ptr = &mut arg as *mut _ as *mut c_void;
+        let unblock_ptr = &unblock as *const U as *mut c_void;
+        unsafe {
+            // With RB_NOGVL_INTR_FAIL pending interrupts are left to us, as
+            // handling them in rb_nogvl would unwind through Rust frames.
+            rb_nogvl(
+                Some(call_without_gvl::<F, Option<R>>),
+                arg_ptr,
+                Some(call_unblock::<U>),
+                unblock_ptr,
+                RB_NOGVL_INTR_FAIL as i32,
+            );
+        }
+        ruby.thread_check_ints()?;
+        if let Some(value) = unsafe { result.assume_init() } {
+            return Ok(value);
+        }
+    }
+}
+
+/// Waits for `future` on `runtime` without the GVL. An interrupt that
+/// raises, such as `Thread#kill` or `Timeout`, stops the wait, dropping
+/// `future`, and is returned as the error.
+pub fn block_on<F: Future>(runtime: &Handle, future: F) -> Result<F::Output, Error> {
+    let unblocked = Notify::new();
+    let mut future = pin!(future);
+    nogvl_interruptible(
+        || {
+            runtime.block_on(async {
+                tokio::select! {
+                    output = &mut future => Some(output),
+                    _ = unblocked.notified() => None,
+                }
+            })
+        },
+        || unblocked.notify_one(),
+    )
+}
+
 unsafe extern "C" fn call_with_gvl<F, R>(arg: *mut c_void) -> *mut c_void
 where
     F: FnOnce() -> R,
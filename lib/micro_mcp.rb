@@ -14,15 +14,20 @@ end
 require_relative "micro_mcp/schema"
 require_relative "micro_mcp/tool_registry"
 require_relative "micro_mcp/prompt_registry"
+require_relative "micro_mcp/resource_registry"
+require_relative "micro_mcp/stop_reason"
+require_relative "micro_mcp/server_handle"
+require_relative "micro_mcp/server_instance"
 require_relative "micro_mcp/server"
 require_relative "micro_mcp/runtime_helpers"
 require_relative "micro_mcp/validation_helpers"
 
 module MicroMcp
+  # Also defined, along with TransportError and ProtocolError, by the native
+  # extension.
   class Error < StandardError; end
-  # Your code goes here...
 
-  def self.start_server
-    Server.start
+  def self.start_server(**options)
+    Server.start(**options)
   end
 end
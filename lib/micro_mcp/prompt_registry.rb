@@ -3,9 +3,19 @@
 module MicroMcp
   module PromptRegistry
     def self.register_prompt(name:, description: nil, arguments: nil, &block)
-      raise ArgumentError, "block required" unless block
+      ServerInstance.default.register_prompt(name: name, description: description, arguments: arguments, &block)
+    end
+
+    def self.replace_prompt(name:, **options, &block)
+      ServerInstance.default.replace_prompt(name: name, **options, &block)
+    end
+
+    def self.unregister_prompt(name)
+      ServerInstance.default.unregister_prompt(name)
+    end
 
-      MicroMcpNative.register_prompt(name, description, arguments, block)
+    def self.clear
+      ServerInstance.default.clear_prompts
     end
   end
 end
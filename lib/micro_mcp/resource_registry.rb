@@ -0,0 +1,19 @@
+# frozen_string_literal: true
+
+module MicroMcp
+  module ResourceRegistry
+    def self.register_resource(uri:, name:, description: nil, mime_type: nil, &block)
+      ServerInstance.default.register_resource(uri: uri, name: name, description: description, mime_type: mime_type, &block)
+    end
+
+    def self.register_resource_template(uri_template:, name:, description: nil, mime_type: nil, &block)
+      ServerInstance.default.register_resource_template(
+        uri_template: uri_template, name: name, description: description, mime_type: mime_type, &block
+      )
+    end
+
+    def self.notify_updated(uri)
+      ServerInstance.default.notify_resource_updated(uri)
+    end
+  end
+end
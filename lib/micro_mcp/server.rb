@@ -2,22 +2,16 @@
 
 module MicroMcp
   module Server
-    def self.start
-      thread = Thread.new do
-        MicroMcpNative.start_server
-      rescue => e
-        warn "Error starting server: #{e.message}"
-      end
+    def self.start(**options)
+      ServerInstance.default.start(**options)
+    end
 
-      begin
-        thread.join
-      rescue Interrupt
-        puts "\nShutting down server..."
-        MicroMcpNative.shutdown_server
-        thread.join
-      end
+    def self.start_in_background(**options)
+      ServerInstance.default.start_in_background(**options)
+    end
 
-      puts "Server stopped."
+    def self.on_reload(&block)
+      ServerInstance.default.on_reload(&block)
     end
   end
 end
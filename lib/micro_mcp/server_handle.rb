@@ -0,0 +1,50 @@
+# frozen_string_literal: true
+
+module MicroMcp
+  # Handle to a server started with ServerInstance#start_in_background.
+  class ServerHandle
+    # The exception that stopped the server, or nil. Transport failures raise
+    # MicroMcp::TransportError and protocol failures MicroMcp::ProtocolError.
+    attr_reader :error
+
+    # The StopReason once the server has stopped without an error, or nil.
+    attr_reader :stop_reason
+
+    def initialize(instance, run, &serve)
+      @instance = instance
+      @run = run
+      @error = nil
+      @stop_reason = nil
+      @thread = Thread.new do
+        @stop_reason = StopReason.from_native(serve.call)
+      rescue => e
+        @error = e
+      end
+    end
+
+    # Calls that were still running when the shutdown grace period ran out
+    # and were aborted, as listed by StopReason#aborted_calls.
+    def aborted_calls
+      @stop_reason ? @stop_reason.aborted_calls : []
+    end
+
+    def running?
+      @thread.alive?
+    end
+
+    # Waits up to +timeout+ seconds, or forever when nil, for the server to
+    # stop. Returns true if it has stopped.
+    def wait(timeout = nil)
+      !@thread.join(timeout).nil?
+    end
+
+    # Shuts the server down and waits for it to stop. Tool calls in flight get
+    # +grace+ seconds, or the configured +shutdown_grace+ when nil, to finish
+    # before they are aborted. Returns true once the server has stopped.
+    def stop(grace: nil)
+      # only stops this run, not a later one of the same instance
+      @instance.shutdown(grace: grace, run: @run)
+      wait
+    end
+  end
+end
@@ -0,0 +1,171 @@
+# frozen_string_literal: true
+
+module MicroMcp
+  # An MCP server with its own tools, prompts, resources, transport and
+  # shutdown state. Several instances can run side by side in one process,
+  # each started on its own thread.
+  class ServerInstance
+    # The instance used by ToolRegistry, PromptRegistry, ResourceRegistry and
+    # MicroMcp.start_server.
+    def self.default
+      @default ||= new(MicroMcpNative::Server.default)
+    end
+
+    def initialize(native = MicroMcpNative::Server.new)
+      @native = native
+    end
+
+    # Behaviour hints a tool can declare with `annotations:`, and the MCP
+    # field each is sent as.
+    TOOL_ANNOTATIONS = {
+      title: "title",
+      read_only: "readOnlyHint",
+      destructive: "destructiveHint",
+      idempotent: "idempotentHint",
+      open_world: "openWorldHint"
+    }.freeze
+
+    def register_tool(name:, title: nil, description: nil, arguments: nil, output_schema: nil,
+      annotations: nil, meta: nil, timeout: nil, &block)
+      raise ArgumentError, "block required" unless block
+
+      details = {"title" => title, "description" => description, "timeout" => timeout}
+      details["annotations"] = tool_annotations(annotations) if annotations
+      details["_meta"] = meta if meta
+      @native.register_tool(name, arguments, output_schema, details.compact, block)
+    end
+
+    def register_prompt(name:, description: nil, arguments: nil, &block)
+      raise ArgumentError, "block required" unless block
+
+      @native.register_prompt(name, description, arguments, block)
+    end
+
+    # Registers a resource that clients can list and read. The block is called
+    # with the URI and the runtime and returns the contents as a String; a
+    # binary (ASCII-8BIT) String is sent base64 encoded.
+    def register_resource(uri:, name:, description: nil, mime_type: nil, &block)
+      raise ArgumentError, "block required" unless block
+
+      @native.register_resource(uri.to_s, name, description, mime_type, block)
+    end
+
+    # Registers a family of resources whose URIs match an RFC 6570 URI
+    # template such as "db://tables/{table}/rows{?limit}". The block is called
+    # with a Hash of the variables in the URI read, and the runtime.
+    def register_resource_template(uri_template:, name:, description: nil, mime_type: nil, &block)
+      raise ArgumentError, "block required" unless block
+
+      @native.register_resource_template(uri_template, name, description, mime_type, block)
+    end
+
+    # Sends notifications/resources/updated to the sessions subscribed to
+    # +uri+. Can be called from any thread, whether or not the server runs.
+    def notify_resource_updated(uri)
+      @native.notify_resource_updated(uri.to_s)
+    end
+
+    # Registers a tool in place of the one already registered as +name+, and
+    # raises ArgumentError when there is none. Takes the same options as
+    # register_tool, which also replaces a tool of the same name.
+    def replace_tool(name:, **options, &block)
+      raise ArgumentError, "no tool named #{name}" unless tool_names.include?(name.to_s)
+
+      register_tool(name: name, **options, &block)
+    end
+
+    def replace_prompt(name:, **options, &block)
+      raise ArgumentError, "no prompt named #{name}" unless prompt_names.include?(name.to_s)
+
+      register_prompt(name: name, **options, &block)
+    end
+
+    # Removes a tool, returning whether it was registered. Calls already
+    # running are not affected; later ones fail as calls to an unknown tool.
+    def unregister_tool(name)
+      @native.unregister_tool(name.to_s)
+    end
+
+    def unregister_prompt(name)
+      @native.unregister_prompt(name.to_s)
+    end
+
+    def clear_tools
+      @native.clear_tools
+    end
+
+    def clear_prompts
+      @native.clear_prompts
+    end
+
+    def tool_names
+      @native.tool_names
+    end
+
+    def prompt_names
+      @native.prompt_names
+    end
+
+    # Registers a block to call, on its own thread, whenever the process
+    # receives SIGHUP while the server runs. Without a block SIGHUP keeps its
+    # default behaviour. Takes effect the next time the server starts.
+    def on_reload(&block)
+      @native.on_reload(block)
+    end
+
+    # Serves this instance until it is shut down, blocking the calling thread,
+    # and returns the StopReason. Raises MicroMcp::TransportError or
+    # MicroMcp::ProtocolError when the server fails.
+    def start(**options)
+      handle = start_in_background(**options)
+
+      begin
+        handle.wait
+      rescue Interrupt
+        puts "\nShutting down server..."
+        handle.stop
+      end
+
+      raise handle.error if handle.error
+
+      puts "Server stopped."
+      handle.stop_reason
+    end
+
+    # Serves this instance on a background thread and returns a ServerHandle
+    # to monitor and stop it, leaving the calling thread free. Raises
+    # RuntimeError when this instance is already running.
+    #
+    # The server's diagnostics, such as the address it listens on or tool
+    # calls that time out, go to +logger+, anything responding to +info+,
+    # +warn+ and +error+ like a Logger. Without one they are written to
+    # $stderr.
+    def start_in_background(logger: nil, **options)
+      native = @native
+      options = options.transform_keys(&:to_s)
+      log = if logger
+        ->(level, message) { logger.public_send(level, message) }
+      else
+        ->(_level, message) { warn message }
+      end
+      run = native.begin_run
+      ServerHandle.new(self, run) { native.start_server(options, log, run) }
+    end
+
+    # Stops the server once its in-flight tool calls have finished, aborting
+    # those still running after +grace+ seconds (default: +shutdown_grace+).
+    # With +run+, the number a ServerHandle holds, only that run is stopped.
+    def shutdown(grace: nil, run: nil)
+      @native.shutdown_server(grace, run)
+    end
+
+    private
+
+    def tool_annotations(annotations)
+      annotations.to_h do |key, value|
+        field = TOOL_ANNOTATIONS.fetch(key.to_sym) { raise ArgumentError, "unknown tool annotation: #{key}" }
+        [field, value]
+      end
+    end
+  end
+end
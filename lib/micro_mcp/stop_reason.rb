@@ -0,0 +1,30 @@
+# frozen_string_literal: true
+
+module MicroMcp
+  # Why a server stopped without an error. +reason+ is one of
+  # :client_disconnected, :shutdown_requested or :signal, +signal+ names the
+  # signal received ("SIGINT", "SIGTERM") and +aborted_calls+ lists the calls
+  # aborted after the shutdown grace period: tool names, and "prompt:" or
+  # "resource:" followed by a prompt name or resource URI.
+  StopReason = Struct.new(:reason, :signal, :aborted_calls, keyword_init: true) do
+    def self.from_native(hash)
+      new(
+        reason: hash["reason"].to_sym,
+        signal: hash["signal"],
+        aborted_calls: hash["aborted_calls"]
+      )
+    end
+
+    def client_disconnected?
+      reason == :client_disconnected
+    end
+
+    def shutdown_requested?
+      reason == :shutdown_requested
+    end
+
+    def signal?
+      reason == :signal
+    end
+  end
+end
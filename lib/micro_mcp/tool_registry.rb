@@ -4,30 +4,24 @@ require_relative "runtime_helpers"
 
 module MicroMcp
   module ToolRegistry
-    def self.register_tool(name:, description: nil, arguments: nil, &block)
-      raise ArgumentError, "block required" unless block
+    def self.register_tool(name:, title: nil, description: nil, arguments: nil, output_schema: nil,
+      annotations: nil, meta: nil, timeout: nil, &block)
+      ServerInstance.default.register_tool(
+        name: name, title: title, description: description, arguments: arguments,
+        output_schema: output_schema, annotations: annotations, meta: meta, timeout: timeout, &block
+      )
+    end
 
-      # Wrap the block with error handling for all tools
-      wrapped_block = proc do |args, runtime|
-        block.call(args, runtime)
-      rescue => e
-        # For test tools that are designed to fail, re-raise the error
-        # so tests can verify the error behavior
-        if name.to_s.include?("error") ||
-            name.to_s.include?("fail") ||
-            name.to_s.include?("use_captured_runtime") ||
-            e.message.include?("McpServer reference")
-          raise e
-        end
+    def self.replace_tool(name:, **options, &block)
+      ServerInstance.default.replace_tool(name: name, **options, &block)
+    end
 
-        # Better error reporting for unexpected failures
-        error_msg = "Tool '#{name}' failed: #{e.message}"
-        puts "ERROR: #{error_msg}"
-        puts "Backtrace: #{e.backtrace.first(3).join("\n")}" if ENV["MCP_DEBUG"]
-        error_msg
-      end
+    def self.unregister_tool(name)
+      ServerInstance.default.unregister_tool(name)
+    end
 
-      MicroMcpNative.register_tool(name, description, arguments, wrapped_block)
+    def self.clear
+      ServerInstance.default.clear_tools
     end
 
     # Enhanced registration with better error handling and validation
@@ -0,0 +1,11 @@
+# frozen_string_literal: true
+
+admin = MicroMcp::ServerInstance.new
+admin.register_tool(name: "admin_status", description: "Reports admin status") do
+  "all systems go"
+end
+Thread.new { admin.start(transport: :unix, socket_path: ENV.fetch("ADMIN_SOCKET")) }
+
+MicroMcp::ToolRegistry.register_tool(name: "public_hello", description: "Greets the public") do
+  "Hello from the public server"
+end
@@ -1,6 +1,11 @@
 # frozen_string_literal: true
 
 require "test_helper"
+require "json"
+require "net/http"
+require "open3"
+require "socket"
+require "tmpdir"
 
 class TestMicroMcp < Minitest::Test
   def test_that_it_has_a_version_number
@@ -11,6 +16,1050 @@ class TestMicroMcp < Minitest::Test
     assert_includes MicroMcp::Runtime.instance_methods(false), :create_message
   end
 
+  def test_server_instances_have_separate_registries
+    admin = MicroMcp::ServerInstance.new
+    public_server = MicroMcp::ServerInstance.new
+
+    admin.register_tool(name: "admin_only") { "admin" }
+    public_server.register_prompt(name: "greeting") { |_args, _runtime| [] }
+
+    assert_equal ["admin_only"], admin.tool_names
+    assert_empty admin.prompt_names
+    assert_empty public_server.tool_names
+    assert_equal ["greeting"], public_server.prompt_names
+    refute_includes MicroMcp::ServerInstance.default.tool_names, "admin_only"
+  end
+
+  def test_tools_and_prompts_can_be_replaced_and_removed_while_running
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "flag") { "old" }
+    server.register_tool(name: "beta") { "beta" }
+    server.register_prompt(name: "greeting") { |_args, _runtime| [] }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      server.replace_tool(name: "flag") { "new" }
+      send_message(socket, id: 2, method: "tools/call", params: {name: "flag"})
+      assert_equal "new", JSON.parse(socket.gets).dig("result", "content", 0, "text")
+
+      assert server.unregister_tool("beta")
+      refute server.unregister_tool("beta")
+      send_message(socket, id: 3, method: "tools/call", params: {name: "beta"})
+      assert JSON.parse(socket.gets).dig("result", "isError")
+      send_message(socket, id: 4, method: "tools/list")
+      assert_equal ["flag"], JSON.parse(socket.gets).dig("result", "tools").map { |tool| tool["name"] }
+    end
+    assert handle.stop(grace: 5)
+
+    assert_raises(ArgumentError) { server.replace_tool(name: "beta") { "beta" } }
+    assert server.unregister_prompt("greeting")
+    server.register_prompt(name: "greeting") { |_args, _runtime| [] }
+    server.clear_tools
+    server.clear_prompts
+    assert_empty server.tool_names
+    assert_empty server.prompt_names
+  end
+
+  def test_registry_changes_notify_clients_once_per_burst
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "first") { "first" }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      response = initialize_session(socket)
+      assert_equal true, response.dig("result", "capabilities", "tools", "listChanged")
+      assert_equal true, response.dig("result", "capabilities", "prompts", "listChanged")
+
+      5.times { |i| server.register_tool(name: "generated_#{i}") { "generated" } }
+      assert_equal "notifications/tools/list_changed", JSON.parse(socket.gets)["method"]
+
+      server.register_prompt(name: "greeting") { |_args, _runtime| [] }
+      assert_equal "notifications/prompts/list_changed", JSON.parse(socket.gets)["method"]
+
+      send_message(socket, id: 2, method: "ping")
+      assert_equal 2, JSON.parse(socket.gets)["id"]
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_default_server_registries_keep_registration_order
+    MicroMcp::ToolRegistry.register_tool(name: "zeta_tool") { "zeta" }
+    MicroMcp::ToolRegistry.register_tool(name: "alpha_tool") { "alpha" }
+    MicroMcp::PromptRegistry.register_prompt(name: "zeta_prompt") { |_args, _runtime| [] }
+    MicroMcp::PromptRegistry.register_prompt(name: "alpha_prompt") { |_args, _runtime| [] }
+    default = MicroMcp::ServerInstance.default
+
+    assert_equal %w[zeta_tool alpha_tool], default.tool_names & %w[alpha_tool zeta_tool]
+    assert_equal %w[zeta_prompt alpha_prompt], default.prompt_names & %w[alpha_prompt zeta_prompt]
+
+    assert MicroMcp::ToolRegistry.unregister_tool("zeta_tool")
+    assert MicroMcp::PromptRegistry.unregister_prompt("zeta_prompt")
+    refute_includes default.tool_names, "zeta_tool"
+    refute_includes default.prompt_names, "zeta_prompt"
+  ensure
+    MicroMcp::ToolRegistry.unregister_tool("alpha_tool")
+    MicroMcp::PromptRegistry.unregister_prompt("alpha_prompt")
+  end
+
+  def test_tools_are_listed_in_registration_order_one_page_at_a_time
+    server = MicroMcp::ServerInstance.new
+    names = %w[zeta alpha mu beta omega]
+    names.each { |name| server.register_tool(name: name) { name } }
+
+    handle, socket_path = start_unix_server(server, page_size: 2)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      listed = []
+      cursor = nil
+      3.times do |i|
+        send_message(socket, id: i + 2, method: "tools/list", params: cursor ? {cursor: cursor} : {})
+        result = JSON.parse(socket.gets)["result"]
+        listed.concat(result["tools"].map { |tool| tool["name"] })
+        cursor = result["nextCursor"]
+      end
+      assert_equal names, listed
+      assert_nil cursor
+
+      send_message(socket, id: 9, method: "tools/list", params: {cursor: "bogus"})
+      assert_equal(-32602, JSON.parse(socket.gets).dig("error", "code"))
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_cursors_survive_the_removal_of_the_entry_they_point_at
+    server = MicroMcp::ServerInstance.new
+    %w[zeta alpha mu beta].each { |name| server.register_tool(name: name) { name } }
+
+    handle, socket_path = start_unix_server(server, page_size: 2)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/list")
+      result = JSON.parse(socket.gets)["result"]
+      assert_equal %w[zeta alpha], result["tools"].map { |tool| tool["name"] }
+
+      assert server.unregister_tool("alpha")
+      server.register_tool(name: "omega") { "omega" }
+      # skips the list_changed notification, whenever it comes
+      response_to = ->(id) { loop { JSON.parse(socket.gets).then { |message| return message if message["id"] == id } } }
+      send_message(socket, id: 3, method: "tools/list", params: {cursor: result["nextCursor"]})
+      response = response_to.call(3)
+      assert_equal %w[mu beta], response.dig("result", "tools").map { |tool| tool["name"] }
+      cursor = response.dig("result", "nextCursor")
+
+      send_message(socket, id: 4, method: "tools/list", params: {cursor: cursor})
+      response = response_to.call(4)
+      assert_equal %w[omega], response.dig("result", "tools").map { |tool| tool["name"] }
+      assert_nil response.dig("result", "nextCursor")
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_tool_input_schemas_reach_clients_unchanged
+    schema = {
+      "type" => "object",
+      "description" => "Moves a point",
+      "$defs" => {"point" => {"type" => "object", "properties" => {"x" => {"type" => "number"}}}},
+      "properties" => {
+        "from" => {"$ref" => "#/$defs/point"},
+        "to" => {"anyOf" => [{"$ref" => "#/$defs/point"}, {"type" => "null"}]},
+        "dry_run" => true
+      },
+      "required" => ["from"],
+      "additionalProperties" => false
+    }
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "move", arguments: schema) { "moved" }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/list")
+      response = JSON.parse(socket.gets)
+      assert_equal schema, response.dig("result", "tools", 0, "inputSchema")
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_tools_are_listed_with_title_annotations_and_meta
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(
+      name: "drop_table",
+      title: "Drop table",
+      annotations: {read_only: false, destructive: true, idempotent: true, open_world: false},
+      meta: {"acme/owner" => "dba"}
+    ) { "dropped" }
+    server.register_tool(name: "plain") { "plain" }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/list")
+      tools = JSON.parse(socket.gets).dig("result", "tools").to_h { |tool| [tool["name"], tool] }
+      assert_equal "Drop table", tools.dig("drop_table", "title")
+      assert_equal(
+        {"readOnlyHint" => false, "destructiveHint" => true, "idempotentHint" => true, "openWorldHint" => false},
+        tools.dig("drop_table", "annotations")
+      )
+      assert_equal({"acme/owner" => "dba"}, tools.dig("drop_table", "_meta"))
+      assert_empty tools["plain"].keys & %w[title annotations _meta]
+    end
+    assert handle.stop(grace: 5)
+
+    assert_raises(ArgumentError) { server.register_tool(name: "x", annotations: {dangerous: true}) { "" } }
+    assert_raises(ArgumentError) { server.register_tool(name: "x", annotations: {read_only: "yes"}) { "" } }
+  end
+
+  def test_register_tool_rejects_invalid_input_schemas
+    server = MicroMcp::ServerInstance.new
+
+    error = assert_raises(ArgumentError) do
+      server.register_tool(name: "bad", arguments: {"type" => "object", "properties" => {"a" => 1}}) { "" }
+    end
+    assert_match(%r{/properties/a}, error.message)
+    assert_raises(ArgumentError) { server.register_tool(name: "bad", arguments: {"type" => "string"}) { "" } }
+    assert_raises(ArgumentError) do
+      server.register_tool(name: "bad", arguments: {"properties" => {"a" => {"$ref" => "#/$defs/missing"}}}) { "" }
+    end
+    error = assert_raises(ArgumentError) do
+      server.register_tool(name: "bad", arguments: {"properties" => {"a" => {"pattern" => "^(?!admin)"}}}) { "" }
+    end
+    assert_match(/unsupported pattern/, error.message)
+    assert_empty server.tool_names
+  end
+
+  def test_invalid_tool_arguments_are_rejected_before_the_tool_runs
+    calls = 0
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "repeat", arguments: {
+      "type" => "object",
+      "properties" => {
+        "text" => {"type" => "string", "pattern" => "^[a-z]+$"},
+        "times" => {"type" => "integer", "minimum" => 1, "maximum" => 3}
+      },
+      "required" => ["text", "times"]
+    }) do |args, _runtime|
+      calls += 1
+      args["text"] * args["times"]
+    end
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "repeat", arguments: {text: "ABC", times: 5}})
+      error = JSON.parse(socket.gets)["error"]
+      assert_equal(-32602, error["code"])
+      assert_equal ["/text: must match the pattern ^[a-z]+$", "/times: must be at most 3"], error.dig("data", "errors")
+
+      send_message(socket, id: 3, method: "tools/call", params: {name: "repeat", arguments: {text: "ab", times: 2}})
+      assert_equal "abab", JSON.parse(socket.gets).dig("result", "content", 0, "text")
+    end
+    assert_equal 1, calls
+    assert handle.stop(grace: 5)
+  end
+
+  def test_reference_cycles_in_schemas_fail_the_call
+    calls = 0
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "loop", arguments: {"anyOf" => [{"$ref" => "#"}]}) { calls += 1 }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "loop", arguments: {}})
+      result = JSON.parse(socket.gets)["result"]
+      assert result["isError"]
+      assert_match(/Input schema of tool loop cannot be applied: references nest more than 256 deep/, result.dig("content", 0, "text"))
+    end
+    assert_equal 0, calls
+    assert handle.stop(grace: 5)
+  end
+
+  def test_tools_return_structured_content_matching_their_output_schema
+    output_schema = {
+      "type" => "object",
+      "properties" => {"sum" => {"type" => "integer"}},
+      "required" => ["sum"]
+    }
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "sum", output_schema: output_schema) { |args, _runtime| {sum: args["values"].sum} }
+    server.register_tool(name: "broken_sum", output_schema: output_schema) { {sum: "many"} }
+    server.register_tool(name: "list") { [1, 2] }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/list")
+      tools = JSON.parse(socket.gets).dig("result", "tools").to_h { |tool| [tool["name"], tool] }
+      assert_equal output_schema, tools.dig("sum", "outputSchema")
+      refute tools["list"].key?("outputSchema")
+
+      send_message(socket, id: 3, method: "tools/call", params: {name: "sum", arguments: {values: [1, 2, 3]}})
+      result = JSON.parse(socket.gets)["result"]
+      assert_equal({"sum" => 6}, result["structuredContent"])
+      assert_equal({"sum" => 6}, JSON.parse(result.dig("content", 0, "text")))
+
+      send_message(socket, id: 4, method: "tools/call", params: {name: "broken_sum"})
+      result = JSON.parse(socket.gets)["result"]
+      assert result["isError"]
+      assert_includes result.dig("content", 0, "text"), "/sum: expected integer, got string"
+
+      send_message(socket, id: 5, method: "tools/call", params: {name: "list"})
+      assert_equal({"result" => [1, 2]}, JSON.parse(socket.gets).dig("result", "structuredContent"))
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_tools_return_rich_content
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "report") do
+      [
+        MicroMcp::Content.text("Daily report"),
+        MicroMcp::Content.image("\x89PNG".b, mime_type: "image/png"),
+        MicroMcp::Content.resource_link(uri: "file:///tmp/report.csv", name: "report.csv", mime_type: "text/csv"),
+        MicroMcp::Content.resource(uri: "file:///tmp/notes.txt", text: "all good")
+      ]
+    end
+    server.register_tool(name: "chart") { MicroMcp::Content.audio("RIFF".b, mime_type: "audio/wav") }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "report"})
+      content = JSON.parse(socket.gets).dig("result", "content")
+      assert_equal %w[text image resource_link resource], content.map { |block| block["type"] }
+      assert_equal ["\x89PNG".b].pack("m0"), content[1]["data"]
+      assert_equal "image/png", content[1]["mimeType"]
+      assert_equal "report.csv", content[2]["name"]
+      assert_equal({"uri" => "file:///tmp/notes.txt", "text" => "all good"}, content[3]["resource"])
+
+      send_message(socket, id: 3, method: "tools/call", params: {name: "chart"})
+      assert_equal "audio", JSON.parse(socket.gets).dig("result", "content", 0, "type")
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_content_resource_requires_text_or_blob
+    assert_raises(ArgumentError) { MicroMcp::Content.resource(uri: "file:///x") }
+    assert_equal({"type" => "text", "text" => "hi"}, MicroMcp::Content.text("hi").to_h)
+  end
+
+  def test_tools_return_error_results_and_exceptions_become_error_results
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "weather") do
+      MicroMcp::ErrorResult.new("Unknown city: Atlantis", MicroMcp::Content.text("Try a real city"))
+    end
+    server.register_tool(name: "buggy") { raise KeyError, "key not found: :city" }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "weather"})
+      result = JSON.parse(socket.gets)["result"]
+      assert result["isError"]
+      assert_equal ["Unknown city: Atlantis", "Try a real city"], result["content"].map { |block| block["text"] }
+
+      send_message(socket, id: 3, method: "tools/call", params: {name: "buggy"})
+      result = JSON.parse(socket.gets)["result"]
+      assert result["isError"]
+      assert_equal "KeyError: key not found: :city", result.dig("content", 0, "text")
+      assert_equal({"class" => "KeyError", "message" => "key not found: :city"}, result.dig("_meta", "exception"))
+    end
+    assert handle.stop(grace: 5)
+
+    handle, socket_path = start_unix_server(server, error_backtraces: true)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "buggy"})
+      backtrace = JSON.parse(socket.gets).dig("result", "_meta", "exception", "backtrace")
+      assert_match(/test_micro_mcp\.rb/, backtrace.first)
+      assert_operator backtrace.size, :<=, 10
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_error_result_requires_content
+    assert_raises(ArgumentError) { MicroMcp::ErrorResult.new }
+  end
+
+  def test_start_in_background_returns_a_handle
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "ping") { "pong" }
+
+    handle, socket_path = start_unix_server(server)
+    assert handle.running?
+    refute handle.wait(0.1), "server should still be running"
+
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+    end
+
+    assert handle.stop(grace: 5)
+    refute handle.running?
+    assert_nil handle.error
+  end
+
+  def test_stopping_a_stale_handle_leaves_a_later_run_alone
+    server = MicroMcp::ServerInstance.new
+    first, _socket_path = start_unix_server(server)
+    assert first.stop(grace: 1)
+
+    second, _socket_path = start_unix_server(server)
+    assert first.stop(grace: 1)
+    refute second.wait(0.2), "later run should still be running"
+    assert second.running?
+    assert second.stop(grace: 1)
+  end
+
+  def test_stop_right_after_starting_stops_the_run
+    Dir.mktmpdir do |dir|
+      handle = MicroMcp::ServerInstance.new.start_in_background(transport: :unix, socket_path: File.join(dir, "mcp.sock"))
+
+      assert handle.stop(grace: 1)
+      assert handle.stop_reason.shutdown_requested?
+    end
+  end
+
+  def test_stop_waits_for_in_flight_tool_calls
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "slow_echo") do
+      sleep 0.5
+      "done"
+    end
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "slow_echo"})
+      sleep 0.1
+
+      stopper = Thread.new { handle.stop(grace: 5) }
+      response = JSON.parse(socket.gets)
+      assert_equal "done", response.dig("result", "content", 0, "text")
+      assert stopper.value
+    end
+    assert_empty handle.aborted_calls
+  end
+
+  def test_stop_aborts_tool_calls_still_running_after_the_grace_period
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "stuck") do
+      sleep 30
+      "never"
+    end
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "stuck"})
+      sleep 0.1
+
+      started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+      assert handle.stop(grace: 0.2)
+      assert_operator Process.clock_gettime(Process::CLOCK_MONOTONIC) - started, :<, 5
+    end
+    assert_equal ["stuck"], handle.aborted_calls
+  end
+
+  def test_aborted_prompts_and_resource_reads_are_labelled_by_kind
+    server = MicroMcp::ServerInstance.new
+    server.register_prompt(name: "stuck") { |_args, _runtime| sleep 30 }
+    server.register_resource(uri: "file:///stuck", name: "stuck") { |_uri, _runtime| sleep 30 }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "prompts/get", params: {name: "stuck"})
+      send_message(socket, id: 3, method: "resources/read", params: {uri: "file:///stuck"})
+      sleep 0.1
+      assert handle.stop(grace: 0.2)
+    end
+    assert_equal ["prompt:stuck", "resource:file:///stuck"], handle.aborted_calls
+  end
+
+  def test_slow_tool_calls_do_not_block_other_requests
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "slow_echo") do
+      sleep 1
+      "slow"
+    end
+    server.register_tool(name: "quick_echo") { "quick" }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "slow_echo"})
+      send_message(socket, id: 3, method: "tools/list")
+      send_message(socket, id: 4, method: "tools/call", params: {name: "quick_echo"})
+
+      responses = Array.new(3) { JSON.parse(socket.gets) }
+      assert_equal [3, 4, 2], responses.map { |response| response["id"] }
+      assert_equal "quick", responses[1].dig("result", "content", 0, "text")
+      assert_equal "slow", responses[2].dig("result", "content", 0, "text")
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_worker_threads_limits_concurrent_calls
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "slow_echo") do
+      sleep 0.5
+      "slow"
+    end
+
+    handle, socket_path = start_unix_server(server, worker_threads: 1)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "slow_echo"})
+      send_message(socket, id: 3, method: "tools/call", params: {name: "slow_echo"})
+
+      responses = Array.new(2) { JSON.parse(socket.gets) }
+      assert_equal [2, 3], responses.map { |response| response["id"] }
+      assert_operator Process.clock_gettime(Process::CLOCK_MONOTONIC) - started, :>=, 1
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_tool_calls_that_run_too_long_are_aborted
+    server = MicroMcp::ServerInstance.new
+    finished = Queue.new
+    server.register_tool(name: "hang") do
+      sleep 10
+      "done"
+    ensure
+      finished << true
+    end
+    server.register_tool(name: "patient", timeout: 5) do
+      sleep 0.5
+      "done"
+    end
+
+    handle, socket_path = start_unix_server(server, tool_timeout: 0.2)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "hang"})
+      result = JSON.parse(socket.gets)["result"]
+      assert result["isError"]
+      assert_match(/hang timed out/, result.dig("content", 0, "text"))
+      assert finished.pop(timeout: 1)
+
+      send_message(socket, id: 3, method: "tools/call", params: {name: "patient"})
+      assert_equal "done", JSON.parse(socket.gets).dig("result", "content", 0, "text")
+    end
+    assert handle.stop(grace: 5)
+
+    assert_raises(ArgumentError) { server.register_tool(name: "x", timeout: 0) { "" } }
+  end
+
+  def test_timed_out_tool_calls_are_answered_when_the_kill_cannot_land
+    server = MicroMcp::ServerInstance.new
+    finished = Queue.new
+    server.register_tool(name: "stuck", timeout: 0.2) do
+      # stands in for C code that Ruby cannot interrupt
+      Thread.handle_interrupt(Object => :never) { sleep 3 }
+      "done"
+    ensure
+      finished << true
+    end
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "stuck"})
+      result = JSON.parse(socket.gets)["result"]
+      assert_operator Process.clock_gettime(Process::CLOCK_MONOTONIC) - started, :<, 2.5
+      assert result["isError"]
+      assert_match(/stuck timed out/, result.dig("content", 0, "text"))
+      assert finished.pop(timeout: 5)
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_diagnostics_go_to_the_logger
+    logger = Class.new do
+      attr_reader :messages
+
+      def initialize
+        @messages = Queue.new
+      end
+
+      %i[info warn error].each do |level|
+        define_method(level) { |message| @messages << [level, message] }
+      end
+    end.new
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "hang") { sleep 10 }
+
+    handle, socket_path = start_unix_server(server, tool_timeout: 0.2, logger: logger)
+    assert_equal :info, logger.messages.pop(timeout: 1)&.first
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "hang"})
+      socket.gets
+      send_message(socket, id: 3, method: "tools/call", params: {name: "hang"})
+      sleep 0.1
+      assert handle.stop(grace: 0)
+    end
+
+    assert_equal [:warn, "Tool hang timed out after 200ms"], logger.messages.pop(timeout: 1)
+    assert_equal [:warn, "Aborted calls still running after the shutdown grace period: hang"],
+      logger.messages.pop(timeout: 1)
+  end
+
+  def test_tool_calls_waiting_on_the_client_are_aborted_on_timeout
+    server = MicroMcp::ServerInstance.new
+    finished = Queue.new
+    server.register_tool(name: "ask", timeout: 0.2) do |_args, runtime|
+      runtime.create_message({
+        "messages" => [{"role" => "user", "content" => {"type" => "text", "text" => "Hello?"}}],
+        "maxTokens" => 10
+      })
+    ensure
+      finished << true
+    end
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "ask"})
+      assert_equal "sampling/createMessage", JSON.parse(socket.gets)["method"]
+
+      # never answered, so only the timeout ends the call
+      result = JSON.parse(socket.gets)["result"]
+      assert result["isError"]
+      assert_match(/ask timed out/, result.dig("content", 0, "text"))
+      assert finished.pop(timeout: 1)
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_cancelled_tool_calls_stop_without_a_response
+    server = MicroMcp::ServerInstance.new
+    stopped = Queue.new
+    server.register_tool(name: "poll") do |_args, runtime|
+      sleep 0.01 until runtime.cancelled?
+      stopped << :cooperated
+      "cancelled"
+    end
+    server.register_tool(name: "hang") do
+      sleep 10
+    ensure
+      stopped << :killed
+    end
+    server.register_tool(name: "ask") do |_args, runtime|
+      runtime.create_message({
+        "messages" => [{"role" => "user", "content" => {"type" => "text", "text" => "Hello?"}}],
+        "maxTokens" => 10
+      })
+    ensure
+      stopped << :stopped_asking
+    end
+    server.register_tool(name: "echo") { |args| args["text"] }
+
+    # one thread, which must be replaced after each kill for echo to run
+    handle, socket_path = start_unix_server(server, cancel_grace: 0.2, worker_threads: 1)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "poll"})
+      send_message(socket, method: "notifications/cancelled", params: {requestId: 2, reason: "user aborted"})
+      assert_equal :cooperated, stopped.pop(timeout: 2)
+
+      send_message(socket, id: 3, method: "tools/call", params: {name: "hang"})
+      send_message(socket, method: "notifications/cancelled", params: {requestId: 3})
+      assert_equal :killed, stopped.pop(timeout: 2)
+
+      send_message(socket, id: 4, method: "tools/call", params: {name: "ask"})
+      assert_equal "sampling/createMessage", JSON.parse(socket.gets)["method"]
+      send_message(socket, method: "notifications/cancelled", params: {requestId: 4})
+      assert_equal :stopped_asking, stopped.pop(timeout: 2)
+
+      send_message(socket, id: 5, method: "tools/call", params: {name: "echo", arguments: {text: "still here"}})
+      assert_equal "still here", JSON.parse(socket.gets).dig("result", "content", 0, "text")
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_tools_report_progress_when_the_client_asks_for_it
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "index") do |_args, runtime|
+      runtime.report_progress(1, total: 2, message: "halfway")
+      runtime.report_progress(2)
+      "indexed"
+    end
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "tools/call", params: {name: "index", _meta: {progressToken: "job-1"}})
+      first, second, response = Array.new(3) { JSON.parse(socket.gets) }
+      assert_equal "notifications/progress", first["method"]
+      assert_equal({"progressToken" => "job-1", "progress" => 1.0, "total" => 2.0, "message" => "halfway"}, first["params"])
+      assert_equal({"progressToken" => "job-1", "progress" => 2.0}, second["params"])
+      assert_equal "indexed", response.dig("result", "content", 0, "text")
+
+      send_message(socket, id: 3, method: "tools/call", params: {name: "index"})
+      assert_equal 3, JSON.parse(socket.gets)["id"]
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_resources_can_be_listed_and_read
+    server = MicroMcp::ServerInstance.new
+    server.register_resource(uri: "file:///etc/app.yml", name: "config", mime_type: "text/yaml") do |uri, _runtime|
+      "# #{uri}"
+    end
+    server.register_resource(uri: "file:///logo.png", name: "logo", description: "The logo", mime_type: "image/png") do
+      "\x89PNG".b
+    end
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      capabilities = initialize_session(socket).dig("result", "capabilities")
+      assert_equal({"listChanged" => true, "subscribe" => true}, capabilities["resources"])
+
+      send_message(socket, id: 2, method: "resources/list")
+      resources = JSON.parse(socket.gets).dig("result", "resources")
+      assert_equal ["config", "logo"], resources.map { |resource| resource["name"] }
+      assert_equal "The logo", resources[1]["description"]
+
+      send_message(socket, id: 3, method: "resources/read", params: {uri: "file:///etc/app.yml"})
+      contents = JSON.parse(socket.gets).dig("result", "contents")
+      assert_equal [{"uri" => "file:///etc/app.yml", "mimeType" => "text/yaml", "text" => "# file:///etc/app.yml"}], contents
+
+      send_message(socket, id: 4, method: "resources/read", params: {uri: "file:///logo.png"})
+      contents = JSON.parse(socket.gets).dig("result", "contents")
+      assert_equal "\x89PNG".b, contents[0]["blob"].unpack1("m")
+
+      send_message(socket, id: 5, method: "resources/read", params: {uri: "file:///missing"})
+      assert_equal(-32002, JSON.parse(socket.gets).dig("error", "code"))
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_errors_before_initialization_are_answered
+    server = MicroMcp::ServerInstance.new
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      send_message(socket, id: 1, method: "resources/read", params: {uri: "file:///missing"})
+      assert_equal(-32002, JSON.parse(socket.gets).dig("error", "code"))
+
+      send_message(socket, id: 2, method: "no/such/method")
+      assert_equal 2, JSON.parse(socket.gets)["id"]
+
+      initialize_session(socket)
+    end
+
+    UNIXSocket.open(socket_path) do |socket|
+      send_message(socket, id: 1, method: "initialize", params: {
+        protocolVersion: "2999-01-01",
+        capabilities: {},
+        clientInfo: {name: "test", version: "1"}
+      })
+      assert JSON.parse(socket.gets)["error"]
+      assert_nil socket.gets
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_resource_templates_pass_the_uri_variables_to_the_handler
+    server = MicroMcp::ServerInstance.new
+    server.register_resource_template(uri_template: "db://tables/{table}/rows{?limit}", name: "rows",
+      mime_type: "application/json") do |variables, _runtime|
+      variables.to_json
+    end
+    server.register_resource(uri: "db://tables/secrets/rows", name: "hidden") { "[]" }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      initialize_session(socket)
+      send_message(socket, id: 2, method: "resources/templates/list")
+      templates = JSON.parse(socket.gets).dig("result", "resourceTemplates")
+      assert_equal [{"uriTemplate" => "db://tables/{table}/rows{?limit}", "name" => "rows", "mimeType" => "application/json"}], templates
+
+      read = lambda do |id, uri|
+        send_message(socket, id: id, method: "resources/read", params: {uri: uri})
+        JSON.parse(socket.gets)
+      end
+      contents = read.call(3, "db://tables/users/rows?limit=10").dig("result", "contents", 0)
+      assert_equal "db://tables/users/rows?limit=10", contents["uri"]
+      assert_equal({"table" => "users", "limit" => "10"}, JSON.parse(contents["text"]))
+      assert_equal({"table" => "a b"}, JSON.parse(read.call(4, "db://tables/a%20b/rows").dig("result", "contents", 0, "text")))
+      assert_equal "[]", read.call(5, "db://tables/secrets/rows").dig("result", "contents", 0, "text")
+      assert_equal(-32002, read.call(6, "db://tables/a/b/rows").dig("error", "code"))
+    end
+    assert handle.stop(grace: 5)
+
+    assert_raises(ArgumentError) { server.register_resource_template(uri_template: "db://{table", name: "bad") { "" } }
+  end
+
+  def test_subscribed_sessions_are_notified_of_resource_updates
+    server = MicroMcp::ServerInstance.new
+    server.register_resource(uri: "build://status", name: "build_status") { "passing" }
+    server.register_resource_template(uri_template: "log://{name}", name: "log") { |variables, _runtime| variables["name"] }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |socket|
+      capabilities = initialize_session(socket).dig("result", "capabilities")
+      assert capabilities.dig("resources", "subscribe")
+
+      send_message(socket, id: 2, method: "resources/subscribe", params: {uri: "build://status"})
+      assert_equal({}, JSON.parse(socket.gets)["result"])
+      send_message(socket, id: 3, method: "resources/subscribe", params: {uri: "log://app"})
+      assert_equal({}, JSON.parse(socket.gets)["result"])
+      send_message(socket, id: 4, method: "resources/subscribe", params: {uri: "unknown://x"})
+      assert_equal(-32002, JSON.parse(socket.gets).dig("error", "code"))
+
+      Thread.new { server.notify_resource_updated("log://other") }.join
+      Thread.new { server.notify_resource_updated("build://status") }.join
+      notification = JSON.parse(socket.gets)
+      assert_equal "notifications/resources/updated", notification["method"]
+      assert_equal({"uri" => "build://status"}, notification["params"])
+
+      send_message(socket, id: 5, method: "resources/unsubscribe", params: {uri: "build://status"})
+      assert_equal 5, JSON.parse(socket.gets)["id"]
+      server.notify_resource_updated("build://status")
+      server.notify_resource_updated("log://app")
+      assert_equal({"uri" => "log://app"}, JSON.parse(socket.gets)["params"])
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_unsubscribed_sessions_are_not_notified_of_resource_updates
+    server = MicroMcp::ServerInstance.new
+    server.register_resource(uri: "build://status", name: "build_status") { "passing" }
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) do |unsubscribed|
+      UNIXSocket.open(socket_path) do |subscribed|
+        initialize_session(unsubscribed)
+        initialize_session(subscribed)
+        send_message(unsubscribed, id: 2, method: "resources/subscribe", params: {uri: "build://status"})
+        assert_equal({}, JSON.parse(unsubscribed.gets)["result"])
+        send_message(unsubscribed, id: 3, method: "resources/unsubscribe", params: {uri: "build://status"})
+        assert_equal({}, JSON.parse(unsubscribed.gets)["result"])
+        send_message(subscribed, id: 2, method: "resources/subscribe", params: {uri: "build://status"})
+        assert_equal({}, JSON.parse(subscribed.gets)["result"])
+
+        server.notify_resource_updated("build://status")
+        assert_equal "notifications/resources/updated", JSON.parse(subscribed.gets)["method"]
+
+        send_message(unsubscribed, id: 4, method: "ping")
+        assert_equal 4, JSON.parse(unsubscribed.gets)["id"]
+      end
+    end
+    assert handle.stop(grace: 5)
+  end
+
+  def test_http_requests_from_other_origins_are_refused
+    logs = Queue.new
+    logger = Object.new
+    %i[info warn error].each { |level| logger.define_singleton_method(level) { |message| logs << message } }
+    handle = MicroMcp::ServerInstance.new.start_in_background(
+      transport: :http, port: 0, allowed_origins: ["http://localhost:6274"], logger: logger
+    )
+    url = URI(logs.pop(timeout: 5)[%r{http://\S+}])
+    initialize = {
+      jsonrpc: "2.0", id: 1, method: "initialize",
+      params: {protocolVersion: "2025-06-18", capabilities: {}, clientInfo: {name: "test", version: "1"}}
+    }.to_json
+
+    Net::HTTP.start(url.host, url.port) do |http|
+      response = http.post(url.path, initialize, "Origin" => "http://evil.example")
+      assert_equal "403", response.code
+      response = http.post(url.path, initialize, "Origin" => "http://localhost:6274")
+      assert_equal "200", response.code
+      response = http.post(url.path, initialize)
+      assert_equal "200", response.code
+    end
+  ensure
+    handle&.stop
+  end
+
+  def test_http_messages_go_on_the_stream_of_the_request_they_belong_to
+    logs = Queue.new
+    logger = Object.new
+    %i[info warn error].each { |level| logger.define_singleton_method(level) { |message| logs << message } }
+    started = Queue.new
+    index_gate = Queue.new
+    wait_gate = Queue.new
+    server = MicroMcp::ServerInstance.new
+    server.register_tool(name: "index") do |_args, runtime|
+      started << :index
+      index_gate.pop
+      runtime.report_progress(1, total: 2)
+      "indexed"
+    end
+    server.register_tool(name: "wait") do
+      started << :wait
+      wait_gate.pop
+      "waited"
+    end
+    handle = server.start_in_background(transport: :http, port: 0, logger: logger)
+    url = URI(logs.pop(timeout: 5)[%r{http://\S+}])
+    events = ->(body) { body.scan(/^data: (.*)$/).map { |(data)| JSON.parse(data) } }
+
+    Net::HTTP.start(url.host, url.port) do |http|
+      response = http.post(url.path, {
+        jsonrpc: "2.0", id: 1, method: "initialize",
+        params: {protocolVersion: "2025-06-18", capabilities: {}, clientInfo: {name: "test", version: "1"}}
+      }.to_json)
+      session = {"Mcp-Session-Id" => response["Mcp-Session-Id"]}
+      http.post(url.path, {jsonrpc: "2.0", method: "notifications/initialized"}.to_json, session)
+
+      post = lambda do |id, params|
+        Thread.new do
+          Net::HTTP.start(url.host, url.port) do |client|
+            client.post(url.path, {jsonrpc: "2.0", id: id, method: "tools/call", params: params}.to_json, session).body
+          end
+        end
+      end
+      waiting = post.call(2, {name: "wait"})
+      indexing = post.call(3, {name: "index", _meta: {progressToken: "job-1"}})
+      2.times { started.pop(timeout: 5) }
+      index_gate << true
+      progress, result = events.call(indexing.value)
+      assert_equal "notifications/progress", progress["method"]
+      assert_equal "job-1", progress.dig("params", "progressToken")
+      assert_equal 3, result["id"]
+      wait_gate << true
+      assert_equal [2], events.call(waiting.value).map { |message| message["id"] }
+
+      # held until the client opens a standalone stream
+      server.register_tool(name: "later") { "later" }
+      sleep 0.3
+      TCPSocket.open(url.host, url.port) do |socket|
+        socket.write("GET #{url.path} HTTP/1.1\r\nHost: #{url.host}\r\n" \
+          "Mcp-Session-Id: #{session["Mcp-Session-Id"]}\r\nAccept: text/event-stream\r\n\r\n")
+        line = socket.gets until line&.start_with?("data: ")
+        assert_equal "notifications/tools/list_changed", JSON.parse(line.delete_prefix("data: "))["method"]
+      end
+    end
+  ensure
+    handle&.stop
+  end
+
+  def test_worker_threads_must_be_positive
+    error = assert_raises(ArgumentError) do
+      MicroMcp::ServerInstance.new.start(transport: :unix, socket_path: "unused.sock", worker_threads: 0)
+    end
+    assert_match(/worker_threads/, error.message)
+  end
+
+  def test_shutdown_grace_must_fit_a_duration
+    error = assert_raises(ArgumentError) do
+      MicroMcp::ServerInstance.new.start(transport: :unix, socket_path: "unused.sock", shutdown_grace: 1e300)
+    end
+    assert_match(/shutdown_grace/, error.message)
+  end
+
+  def test_background_start_failure_is_reported_on_the_handle
+    Dir.mktmpdir do |dir|
+      not_a_socket = File.join(dir, "plain_file")
+      File.write(not_a_socket, "")
+
+      handle = MicroMcp::ServerInstance.new.start_in_background(transport: :unix, socket_path: not_a_socket)
+
+      assert handle.wait(5)
+      refute handle.running?
+      assert_kind_of MicroMcp::TransportError, handle.error
+      assert_match(/not a socket/, handle.error.message)
+      assert_nil handle.stop_reason
+    end
+  end
+
+  def test_unix_sockets_in_use_are_not_taken_over
+    first, socket_path = start_unix_server(MicroMcp::ServerInstance.new)
+    second = MicroMcp::ServerInstance.new.start_in_background(transport: :unix, socket_path: socket_path)
+
+    assert second.wait(5)
+    assert_kind_of MicroMcp::TransportError, second.error
+    assert_match(/another server is listening/, second.error.message)
+    assert first.running?
+    UNIXSocket.open(socket_path) { |socket| initialize_session(socket) }
+    assert first.stop(grace: 1)
+    refute File.exist?(socket_path)
+  end
+
+  def test_stop_reason_reports_a_requested_shutdown
+    handle, _socket_path = start_unix_server(MicroMcp::ServerInstance.new)
+
+    assert handle.stop(grace: 1)
+    assert handle.stop_reason.shutdown_requested?
+    assert_nil handle.stop_reason.signal
+    assert_empty handle.stop_reason.aborted_calls
+  end
+
+  def test_shutdown_requests_do_not_outlive_the_run
+    server = MicroMcp::ServerInstance.new
+    handle, _socket_path = start_unix_server(server)
+    assert handle.stop(grace: 1)
+    assert handle.stop(grace: 1)
+    server.shutdown
+
+    handle, socket_path = start_unix_server(server)
+    UNIXSocket.open(socket_path) { |socket| initialize_session(socket) }
+    assert handle.running?
+    assert handle.stop(grace: 1)
+    assert handle.stop_reason.shutdown_requested?
+  end
+
+  def test_stop_reason_reports_a_disconnected_stdio_client
+    script = <<~RUBY
+      require "micro_mcp"
+      reason = MicroMcp::ServerInstance.new.start
+      warn "reason=\#{reason.reason}"
+    RUBY
+    lib = File.expand_path("../lib", __dir__)
+
+    _stdout, stderr, status = Open3.capture3(RbConfig.ruby, "-I", lib, "-e", script, stdin_data: "")
+
+    assert status.success?, stderr
+    assert_includes stderr, "reason=client_disconnected"
+  end
+
+  def test_sighup_reloads_and_configured_signals_shut_down
+    script = <<~RUBY
+      require "micro_mcp"
+      server = MicroMcp::ServerInstance.new
+      reloaded = Queue.new
+      server.on_reload { reloaded << true }
+      handle = server.start_in_background(transport: :unix, socket_path: ARGV[0], shutdown_signals: ["USR1"])
+      sleep 0.05 until File.exist?(ARGV[0])
+      Process.kill("HUP", Process.pid)
+      reloaded.pop
+      warn "running=\#{handle.running?}"
+      Process.kill("USR1", Process.pid)
+      handle.wait
+      warn "reason=\#{handle.stop_reason.reason} signal=\#{handle.stop_reason.signal}"
+    RUBY
+    lib = File.expand_path("../lib", __dir__)
+    socket_path = File.join(Dir.tmpdir, "micro_mcp_signals_#{Process.pid}.sock")
+
+    _stdout, stderr, status = Open3.capture3(RbConfig.ruby, "-I", lib, "-e", script, socket_path)
+
+    assert status.success?, stderr
+    assert_includes stderr, "running=true"
+    assert_includes stderr, "reason=signal signal=SIGUSR1"
+  end
+
+  def test_sighup_cannot_both_reload_and_shut_down
+    server = MicroMcp::ServerInstance.new
+    server.on_reload {}
+
+    error = assert_raises(ArgumentError) do
+      server.start(transport: :unix, socket_path: "unused.sock", shutdown_signals: ["HUP"])
+    end
+    assert_match(/SIGHUP/, error.message)
+  end
+
+  def test_errors_share_a_common_ancestor
+    assert_operator MicroMcp::TransportError, :<, MicroMcp::Error
+    assert_operator MicroMcp::ProtocolError, :<, MicroMcp::Error
+  end
+
   def test_it_does_something_useful
     assert true
   end
@@ -101,4 +1150,29 @@ class TestMicroMcp < Minitest::Test
       assert_equal 0, status.exitstatus, "Server should exit cleanly with status 0, got #{status.exitstatus}"
     end
   end
+
+  private
+
+  def start_unix_server(server, **options)
+    socket_path = File.join(Dir.tmpdir, "micro_mcp_test_#{Process.pid}_#{object_id}.sock")
+    handle = server.start_in_background(transport: :unix, socket_path: socket_path, **options)
+    sleep(0.05) until File.exist?(socket_path) || !handle.running?
+    [handle, socket_path]
+  end
+
+  def send_message(socket, **message)
+    socket.puts({jsonrpc: "2.0", **message}.to_json)
+  end
+
+  def initialize_session(socket)
+    send_message(socket, id: 1, method: "initialize", params: {
+      protocolVersion: "2025-06-18",
+      capabilities: {},
+      clientInfo: {name: "test", version: "1"}
+    })
+    response = JSON.parse(socket.gets)
+    assert_equal 1, response["id"]
+    send_message(socket, method: "notifications/initialized")
+    response
+  end
 end